@@ -0,0 +1,116 @@
+use chrono::{DateTime, Local};
+use log::info;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+// ACCESS_LOG_TARGET is the log target of the access log lines, so they can be routed to their
+// own file, away from the debug log
+pub const ACCESS_LOG_TARGET: &str = "access";
+
+// AccessLogEntry describes a single handled request
+pub struct AccessLogEntry<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub received_at: DateTime<Local>,
+    pub request_line: &'a str,
+    pub status: u16,
+    pub bytes: usize,
+    pub referer: &'a str,
+    pub user_agent: &'a str,
+}
+
+pub fn log(entry: &AccessLogEntry) {
+    info!(target: ACCESS_LOG_TARGET, "{}", format_combined(entry));
+}
+
+// format_combined formats the entry in the Apache Combined Log Format:
+// %h %l %u %t "%r" %>s %b "%{Referer}i" "%{User-agent}i"
+pub fn format_combined(entry: &AccessLogEntry) -> String {
+    let client_ip = match entry.client_ip {
+        Some(ip) => ip.to_string(),
+        None => "-".to_string(),
+    };
+    let bytes = match entry.bytes {
+        0 => "-".to_string(),
+        n => n.to_string(),
+    };
+
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+        client_ip,
+        entry.received_at.format("%d/%b/%Y:%H:%M:%S %z"),
+        escape(entry.request_line),
+        entry.status,
+        bytes,
+        escape(entry.referer),
+        escape(entry.user_agent),
+    )
+}
+
+// escape makes the value safe to put in quotes, the same way Apache does it; missing values
+// are logged as -
+fn escape(value: &str) -> String {
+    if value.is_empty() {
+        return "-".to_string();
+    }
+
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_ascii_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u8);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_combined, AccessLogEntry};
+    use chrono::{FixedOffset, TimeZone};
+
+    #[test]
+    fn test_format_combined() {
+        let received_at = FixedOffset::west_opt(7 * 3600)
+            .unwrap()
+            .with_ymd_and_hms(2000, 10, 10, 13, 55, 36)
+            .unwrap()
+            .into();
+
+        let entry = AccessLogEntry {
+            client_ip: Some("127.0.0.1".parse().unwrap()),
+            received_at,
+            request_line: "GET /l/abc HTTP/1.1",
+            status: 301,
+            bytes: 2326,
+            referer: "http://www.example.com/start.html",
+            user_agent: "Mozilla/4.08 [en] (Win98; I ;Nav)",
+        };
+        let line = format_combined(&entry);
+        // the time is rendered in the local timezone of the machine
+        let time = received_at.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        assert_eq!(
+            line,
+            format!(
+                "127.0.0.1 - - [{}] \"GET /l/abc HTTP/1.1\" 301 2326 \"http://www.example.com/start.html\" \"Mozilla/4.08 [en] (Win98; I ;Nav)\"",
+                time
+            )
+        );
+
+        let entry = AccessLogEntry {
+            client_ip: None,
+            received_at,
+            request_line: "",
+            status: 404,
+            bytes: 0,
+            referer: "",
+            user_agent: "evil\" agent\x1b[31m",
+        };
+        let line = format_combined(&entry);
+        assert!(line.starts_with("- - - ["));
+        assert!(line.ends_with("] \"-\" 404 - \"-\" \"evil\\\" agent\\x1b[31m\""));
+    }
+}
@@ -0,0 +1,325 @@
+use http::StatusCode;
+use log::debug;
+use redis::{Commands, Connection, RedisError, Script};
+use serde_json::Value;
+use std::net::TcpStream;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+extern crate redis;
+use crate::{
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    metrics::Metrics,
+    new_handler::{is_reserved_id, is_valid_custom_id},
+    stats_handler::get_id_param,
+    telemetry,
+    url_record::{URLRecord, MAX_ALIASES},
+};
+
+// ADD_ALIAS_SCRIPT takes the alias key and stores the link listing the alias at once, so an
+// alias never points to a link which doesn't know of it; the alias can't be the id of a link
+// (KEYS[1]) nor the alias of another one (KEYS[2])
+const ADD_ALIAS_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+if not redis.call('SET', KEYS[2], ARGV[1], 'NX') then
+    return 0
+end
+redis.call('SET', KEYS[3], ARGV[2])
+return 1
+"#;
+
+// alias_key points from an alias to the id of its link
+pub fn alias_key(alias: &str) -> String {
+    format!("short_url_alias::{}", alias)
+}
+
+// get_url_record returns the link with the given id, or the link the id is an alias of
+pub fn get_url_record(
+    redis_conn: &mut Connection,
+    id: &str,
+) -> Result<Option<URLRecord>, RedisError> {
+    let url_key = format!("short_url::{}", id);
+    let url_record: Option<String> = telemetry::traced("redis GET", || redis_conn.get(&url_key))?;
+    if let Some(url_record) = url_record {
+        return Ok(Some(URLRecord::from_json(id.to_string(), &url_record)));
+    }
+
+    let link_id: Option<String> = telemetry::traced("redis GET", || redis_conn.get(alias_key(id)))?;
+    let link_id = match link_id {
+        Some(link_id) => link_id,
+        None => return Ok(None),
+    };
+    let url_key = format!("short_url::{}", link_id);
+    let url_record: Option<String> = telemetry::traced("redis GET", || redis_conn.get(&url_key))?;
+    Ok(url_record.map(|url_record| URLRecord::from_json(link_id, &url_record)))
+}
+
+// respond_not_found answers a request for a link which doesn't exist, with a 400 when the id is
+// an alias, as the request only works with the id of the link itself
+pub fn respond_not_found(
+    redis_conn: &mut Connection,
+    metrics: &Metrics,
+    stream: TcpStream,
+    id: &str,
+) {
+    match telemetry::traced("redis GET", || {
+        redis_conn.get::<String, Option<String>>(alias_key(id))
+    }) {
+        Ok(Some(link_id)) => Handlers::respond_with_status_code(
+            stream,
+            StatusCode::BAD_REQUEST.as_u16(),
+            format!("id [{}] is an alias of [{}]", id, link_id),
+        ),
+        Ok(None) => Handlers::respond_with_status_code(
+            stream,
+            StatusCode::NOT_FOUND.as_u16(),
+            format!("url [{}] not found", id),
+        ),
+        Err(err) => {
+            debug!("failed to execute GET for alias [{}]: {}", id, err);
+            metrics.inc_redis_errors();
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                err.to_string(),
+            );
+        }
+    }
+}
+
+pub struct AliasHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+    reserved_ids: Vec<String>,
+}
+
+impl AliasHandler {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<AliasHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(AliasHandler {
+            redis_conn,
+            metrics,
+            reserved_ids: vec![],
+        })
+    }
+
+    pub fn set_reserved_ids(&mut self, reserved_ids: &[String]) {
+        self.reserved_ids = reserved_ids.to_vec();
+    }
+
+    // handle_alias expects the path in form of: /alias?id=<url id>, and the alias to add to the
+    // link in the post body; the link is then reachable through /l/<alias> as well
+    pub fn handle_alias(
+        &mut self,
+        stream: TcpStream,
+        path: &str,
+        post_body: String,
+        content_type: String,
+        actor: &str,
+    ) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let alias = match get_alias_from_post_body(&post_body, &content_type) {
+            Ok(alias) => alias,
+            Err(err) => {
+                debug!("add alias: {}", err);
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+        if is_reserved_id(&alias, &self.reserved_ids) {
+            debug!("alias [{}] is reserved", alias);
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                format!("id [{}] is reserved", alias),
+            );
+            return;
+        }
+
+        let url_key = format!("short_url::{}", id);
+        let mut url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("url [{}] not found", id),
+                );
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        if url_record.deleted_at.is_some() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::CONFLICT.as_u16(),
+                format!("url [{}] is in the trash, restore it first", id),
+            );
+            return;
+        }
+        if url_record.aliases.len() >= MAX_ALIASES {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                format!("a link can have at most {} aliases", MAX_ALIASES),
+            );
+            return;
+        }
+
+        if let Err(err) = history::record_baseline(&mut self.redis_conn, &mut url_record) {
+            debug!("failed to record the history baseline of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+        url_record.aliases.push(alias.clone());
+        url_record.version += 1;
+        let url_record_json = url_record.to_json();
+        debug!(
+            "++ storing url record with new alias: {}",
+            url_record.to_public_value()
+        );
+        let alias_added = match telemetry::traced("redis EVALSHA", || {
+            Script::new(ADD_ALIAS_SCRIPT)
+                .key(format!("short_url::{}", alias))
+                .key(alias_key(&alias))
+                .key(&url_key)
+                .arg(id)
+                .arg(&url_record_json)
+                .invoke::<bool>(&mut self.redis_conn)
+        }) {
+            Ok(alias_added) => alias_added,
+            Err(err) => {
+                debug!("failed to add alias [{}] to [{}]: {}", alias, url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        if !alias_added {
+            debug!("alias [{}] already exists, skipping add", alias);
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                "already exists".to_string(),
+            );
+            return;
+        }
+
+        let entry = HistoryEntry::new(&url_record, actor, "add_alias");
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the new alias of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            url_record.to_public_value().to_string(),
+        );
+    }
+}
+
+// get_alias_from_post_body returns the alias from a json body, e.g. {"alias":"docs"}, or a form
+// one, e.g. alias=docs
+fn get_alias_from_post_body(post_body: &str, content_type: &str) -> Result<String, String> {
+    let alias = match content_type {
+        "application/json" => {
+            let parsed_json: Value =
+                serde_json::from_str(post_body).map_err(|_| "Failed to parse JSON".to_string())?;
+            match parsed_json.get("alias") {
+                Some(alias) => Some(
+                    alias
+                        .as_str()
+                        .ok_or("alias field is not a string".to_string())?
+                        .to_string(),
+                ),
+                None => None,
+            }
+        }
+        "application/x-www-form-urlencoded" => form_urlencoded::parse(post_body.as_bytes())
+            .find(|(name, _)| name == "alias")
+            .map(|(_, value)| value.into_owned()),
+        _ => return Err("Invalid content_type".to_string()),
+    };
+    let alias = alias.unwrap_or_default();
+    if alias.is_empty() {
+        return Err("alias param not found".to_string());
+    }
+    if !is_valid_custom_id(&alias) {
+        return Err(format!(
+            "invalid alias [{}], only letters, digits, '.', '_', '~' and '-' are allowed",
+            alias
+        ));
+    }
+    Ok(alias)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_alias_from_post_body;
+
+    #[test]
+    fn test_get_alias_from_post_body() {
+        let json = "application/json";
+        let form = "application/x-www-form-urlencoded";
+        assert_eq!(
+            get_alias_from_post_body(r#"{"alias":"docs"}"#, json),
+            Ok("docs".to_string())
+        );
+        assert_eq!(
+            get_alias_from_post_body("alias=spring-sale", form),
+            Ok("spring-sale".to_string())
+        );
+        // the /l/ path isn't percent-decoded, so such an alias could never be reached
+        assert!(get_alias_from_post_body("alias=spring%20sale", form).is_err());
+
+        for (body, content_type) in [
+            (r#"{"alias":""}"#, json),
+            (r#"{"alias":1}"#, json),
+            (r#"{"id":"docs"}"#, json),
+            ("not json", json),
+            (r#"{"alias":"docs/faq"}"#, json),
+            ("alias=docs%2B", form),
+            ("alias=docs", "text/plain"),
+        ] {
+            assert!(
+                get_alias_from_post_body(body, content_type).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use chrono::Utc;
+use log::debug;
+use redis::{Commands, Connection, RedisError};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+extern crate redis;
+use crate::geoip::GeoIp;
+use crate::metrics::Metrics;
+use crate::privacy::IpAnonymization;
+use crate::telemetry;
+
+const UNKNOWN_COUNTRY: &str = "unknown";
+const CLICKS_KEY_PREFIX: &str = "short_url_clicks::";
+
+pub fn countries_key(url_id: &str) -> String {
+    format!("short_url_countries::{}", url_id)
+}
+
+// hits of each variant of a split link, by the variant url
+pub fn variants_key(url_id: &str) -> String {
+    format!("short_url_variants::{}", url_id)
+}
+
+// hits of each alias of a link, by the alias id
+pub fn aliases_key(url_id: &str) -> String {
+    format!("short_url_aliases::{}", url_id)
+}
+
+pub fn clicks_key(url_id: &str) -> String {
+    format!("{}{}", CLICKS_KEY_PREFIX, url_id)
+}
+
+// AnalyticsConfig groups the settings of what and how long is recorded about clicks
+pub struct AnalyticsConfig {
+    pub geoip: Option<GeoIp>,
+    pub ip_anonymization: IpAnonymization,
+    pub clicks_retention_days: i64,
+}
+
+// ClickEvent is a single raw click, kept only for the configured retention period
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClickEvent {
+    pub timestamp: i64,
+    pub ip: Option<String>,
+    pub country: Option<String>,
+    // url of the variant served, for split links
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+    // alias id the link was reached through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+}
+
+// Analytics records details about link clicks; when no geoip database is configured,
+// country tagging is silently turned off
+pub struct Analytics {
+    redis_conn: Connection,
+    geoip: Option<GeoIp>,
+    ip_anonymization: IpAnonymization,
+    metrics: Arc<Metrics>,
+}
+
+impl Analytics {
+    pub fn new(
+        redis_conn_string: &String,
+        geoip: Option<GeoIp>,
+        ip_anonymization: IpAnonymization,
+        metrics: Arc<Metrics>,
+    ) -> Result<Analytics, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(Analytics {
+            redis_conn,
+            geoip,
+            ip_anonymization,
+            metrics,
+        })
+    }
+
+    // record_click records a click on the link, which of its variants was served if it's a
+    // split link, and which of its aliases was used if it wasn't reached through its own id
+    pub fn record_click(
+        &mut self,
+        url_id: &str,
+        client_ip: Option<IpAddr>,
+        variant: Option<&str>,
+        alias: Option<&str>,
+    ) {
+        let country = self.geoip.as_ref().map(|geoip| {
+            client_ip
+                .and_then(|ip| geoip.country_code(ip))
+                .unwrap_or_else(|| UNKNOWN_COUNTRY.to_string())
+        });
+        // the raw ip never leaves this function
+        let ip = client_ip.map(|ip| self.ip_anonymization.anonymize(ip));
+        debug!(
+            "click on [{}] from {:?} tagged as [{:?}]",
+            url_id, ip, country
+        );
+
+        if let Some(country) = &country {
+            let res: Result<i64, RedisError> = telemetry::traced("redis HINCRBY", || {
+                self.redis_conn.hincr(countries_key(url_id), country, 1)
+            });
+            if let Err(err) = res {
+                debug!(
+                    "failed to execute HINCRBY for country [{}] of [{}]: {}",
+                    country, url_id, err
+                );
+                self.metrics.inc_redis_errors();
+            }
+        }
+
+        if let Some(variant) = variant {
+            let res: Result<i64, RedisError> = telemetry::traced("redis HINCRBY", || {
+                self.redis_conn.hincr(variants_key(url_id), variant, 1)
+            });
+            if let Err(err) = res {
+                debug!(
+                    "failed to execute HINCRBY for variant [{}] of [{}]: {}",
+                    variant, url_id, err
+                );
+                self.metrics.inc_redis_errors();
+            }
+        }
+
+        if let Some(alias) = alias {
+            let res: Result<i64, RedisError> = telemetry::traced("redis HINCRBY", || {
+                self.redis_conn.hincr(aliases_key(url_id), alias, 1)
+            });
+            if let Err(err) = res {
+                debug!(
+                    "failed to execute HINCRBY for alias [{}] of [{}]: {}",
+                    alias, url_id, err
+                );
+                self.metrics.inc_redis_errors();
+            }
+        }
+
+        let click = ClickEvent {
+            timestamp: Utc::now().timestamp(),
+            ip,
+            country,
+            variant: variant.map(str::to_string),
+            alias: alias.map(str::to_string),
+        };
+        let res: Result<i64, RedisError> = telemetry::traced("redis RPUSH", || {
+            self.redis_conn
+                .rpush(clicks_key(url_id), serde_json::to_string(&click).unwrap())
+        });
+        if let Err(err) = res {
+            debug!("failed to execute RPUSH for click on [{}]: {}", url_id, err);
+            self.metrics.inc_redis_errors();
+        }
+    }
+}
+
+pub fn get_countries(
+    redis_conn: &mut Connection,
+    url_id: &str,
+) -> Result<BTreeMap<String, i64>, RedisError> {
+    telemetry::traced("redis HGETALL", || {
+        redis_conn.hgetall(countries_key(url_id))
+    })
+}
+
+pub fn get_variant_hits(
+    redis_conn: &mut Connection,
+    url_id: &str,
+) -> Result<BTreeMap<String, i64>, RedisError> {
+    telemetry::traced("redis HGETALL", || redis_conn.hgetall(variants_key(url_id)))
+}
+
+pub fn get_alias_hits(
+    redis_conn: &mut Connection,
+    url_id: &str,
+) -> Result<BTreeMap<String, i64>, RedisError> {
+    telemetry::traced("redis HGETALL", || redis_conn.hgetall(aliases_key(url_id)))
+}
+
+pub fn get_clicks_count(redis_conn: &mut Connection, url_id: &str) -> Result<i64, RedisError> {
+    telemetry::traced("redis LLEN", || redis_conn.llen(clicks_key(url_id)))
+}
+
+// wipe removes everything analytics knows about the given link
+pub fn wipe(redis_conn: &mut Connection, url_id: &str) -> Result<i32, RedisError> {
+    telemetry::traced("redis DEL", || {
+        redis_conn.del(&[
+            countries_key(url_id),
+            clicks_key(url_id),
+            variants_key(url_id),
+            aliases_key(url_id),
+        ])
+    })
+}
+
+// purge_expired_clicks drops raw click events older than cutoff (unix timestamp) for all
+// links, returns the number of removed events
+pub fn purge_expired_clicks(redis_conn: &mut Connection, cutoff: i64) -> Result<usize, RedisError> {
+    let clicks_keys: Vec<String> = redis_conn
+        .scan_match(format!("{}*", CLICKS_KEY_PREFIX))?
+        .collect();
+
+    let mut purged = 0;
+    for key in clicks_keys {
+        let events: Vec<String> = redis_conn.lrange(&key, 0, -1)?;
+        let expired = count_expired(&events, cutoff);
+        if expired == 0 {
+            continue;
+        }
+
+        // clicks are pushed in order, so the expired ones are all at the head of the list
+        let _: () = redis_conn.ltrim(&key, expired as isize, -1)?;
+        debug!("purged {} expired clicks from [{}]", expired, key);
+        purged += expired;
+    }
+
+    Ok(purged)
+}
+
+fn count_expired(events: &[String], cutoff: i64) -> usize {
+    events
+        .iter()
+        .take_while(|e| match serde_json::from_str::<ClickEvent>(e) {
+            Ok(click) => click.timestamp < cutoff,
+            // nothing useful can be done with broken events
+            Err(_) => true,
+        })
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::count_expired;
+
+    #[test]
+    fn test_count_expired() {
+        let events: Vec<String> = [
+            r#"{"timestamp":100,"ip":"203.0.113.0","country":null}"#,
+            r#"{"timestamp":200,"ip":null,"country":"RS"}"#,
+            r#"{"timestamp":300,"ip":null,"country":null}"#,
+        ]
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+
+        assert_eq!(count_expired(&events, 50), 0);
+        assert_eq!(count_expired(&events, 100), 0);
+        assert_eq!(count_expired(&events, 201), 2);
+        assert_eq!(count_expired(&events, 1000), 3);
+        assert_eq!(count_expired(&[], 1000), 0);
+        assert_eq!(count_expired(&["broken".to_string()], 0), 1);
+    }
+}
@@ -4,6 +4,8 @@ use redis::{Commands, Connection};
 extern crate chrono;
 extern crate redis;
 
+use crate::telemetry;
+
 const SESSION_KEY_PREFIX: &str = "serj-service-session||";
 const DEFAULT_TTL_DAYS: i64 = 7;
 
@@ -25,30 +27,31 @@ impl AuthService {
             return true;
         }
 
-        if token == "" {
+        if token.is_empty() {
             return false;
         }
 
         let session_key = format!("{}{}", SESSION_KEY_PREFIX, token);
-        let created_at_unix_str: String = match self.redis_conn.get(session_key) {
-            Ok(v) => v,
-            Err(e) => {
-                debug!("failed to find token in sessions [{}]: {}", token, e);
-                return false;
-            }
-        };
+        let created_at_unix_str: String =
+            match telemetry::traced("redis GET", || self.redis_conn.get(session_key)) {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("failed to find token in sessions: {}", e);
+                    return false;
+                }
+            };
 
         debug!(
-            "auth service, checking logged in for [{}], created at: {}",
-            token, created_at_unix_str
+            "auth service, checking logged in session created at: {}",
+            created_at_unix_str
         );
 
-        return is_created_at_valid(Utc::now(), created_at_unix_str);
+        is_created_at_valid(Utc::now(), created_at_unix_str)
     }
 }
 
 fn is_created_at_valid(now: DateTime<Utc>, created_at_unix: String) -> bool {
-    if created_at_unix == "" {
+    if created_at_unix.is_empty() {
         return false;
     }
 
@@ -60,18 +63,14 @@ fn is_created_at_valid(now: DateTime<Utc>, created_at_unix: String) -> bool {
         }
     };
 
-    let created_at_naive = match NaiveDateTime::from_timestamp_opt(created_at_unix, 0) {
+    let created_at: DateTime<Utc> = match DateTime::from_timestamp(created_at_unix, 0) {
         Some(v) => v,
         None => {
-            debug!(
-                "failed to create native date time from ts: {}",
-                created_at_unix
-            );
+            debug!("failed to create date time from ts: {}", created_at_unix);
             return false;
         }
     };
 
-    let created_at: DateTime<Utc> = DateTime::from_utc(created_at_naive, Utc);
     let created_at_with_ttl = match created_at.checked_add_signed(Duration::days(DEFAULT_TTL_DAYS))
     {
         Some(v) => v,
@@ -85,7 +84,7 @@ fn is_created_at_valid(now: DateTime<Utc>, created_at_unix: String) -> bool {
         return false;
     }
 
-    return true;
+    true
 }
 
 #[cfg(test)]
@@ -97,8 +96,8 @@ mod tests {
     #[test]
     fn test_is_created_at_valid() {
         let now: DateTime<Utc> = Utc.with_ymd_and_hms(2022, 12, 25, 0, 0, 0).unwrap();
-        assert_eq!(true, is_created_at_valid(now, "1671731525".to_string())); // 1671731525 = 2022 dec 22
-        assert_eq!(false, is_created_at_valid(now, "1669139064".to_string())); // 1669139064 = 22 nov 22
-        assert_eq!(false, is_created_at_valid(now, "".to_string()));
+        assert!(is_created_at_valid(now, "1671731525".to_string())); // 1671731525 = 2022 dec 22
+        assert!(!is_created_at_valid(now, "1669139064".to_string())); // 1669139064 = 22 nov 22
+        assert!(!is_created_at_valid(now, "".to_string()));
     }
 }
@@ -1,149 +1,269 @@
-use log::{info, trace, warn, LevelFilter};
+use log::{error, info, trace, warn, LevelFilter};
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
-use log4rs::config::{Appender, Config, Root};
+use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
+use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
+use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
+use log4rs::append::rolling_file::RollingFileAppender;
+use log4rs::config::{Appender, Config as LogConfig, Logger, Root};
+use log4rs::encode::json::JsonEncoder;
 use log4rs::encode::pattern::PatternEncoder;
+use log4rs::encode::Encode;
+use log4rs::Handle;
+use rust_url_shortener::access_log::ACCESS_LOG_TARGET;
+use rust_url_shortener::config::{CliArgs, Config, USAGE};
+use rust_url_shortener::geoip::GeoIp;
+use rust_url_shortener::redact::{RedactingEncoder, Redactor};
+use rust_url_shortener::router::Router;
 use rust_url_shortener::server::Server;
+use rust_url_shortener::telemetry::Tracer;
 use std::{
     env, process,
+    sync::atomic::Ordering,
     sync::{Arc, Mutex},
+    thread,
+    time::Duration,
 };
 
 // to run in windows, with redis running in docker, and port:
 // $env:SERJ_REDIS_PASS = 'todo'; .\rust-url-shortener.exe -p 9001
 // or locally:
 // cargo run -- -redispass todo --insecure -port 9001
+// or with a config file (see config.example.toml):
+// cargo run -- --config config.toml
+
+// how many rotated access log files are kept around
+const ACCESS_LOG_ROTATED_FILES: u32 = 5;
 
 fn main() {
-    println!("starting url shortener ...");
+    let args: Vec<String> = env::args().skip(1).collect();
+    let cli_args = match CliArgs::parse(&args) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if cli_args.help {
+        println!("{}", USAGE);
+        return;
+    }
 
-    setup_logger();
+    // all the settings are checked before anything is started
+    let config = match Config::load(&cli_args, |name| env::var(name).ok()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("invalid configuration:\n{}", e);
+            process::exit(1);
+        }
+    };
 
-    let redis_host;
-    match env::var("RUS_REDIS_HOST") {
-        Ok(val) => redis_host = val,
-        Err(_e) => redis_host = "127.0.0.1".to_string(),
-    }
+    println!("starting url shortener ...");
 
-    let redis_conn_string;
-    match env::var("SERJ_REDIS_PASS") {
-        Ok(val) => redis_conn_string = format!("redis://default:{}@{}/", val, redis_host),
-        Err(_e) => {
-            let redis_pass_arg = get_redis_pass_arg();
-            if redis_pass_arg != "" {
-                redis_conn_string = format!("redis://default:{}@{}/", redis_pass_arg, redis_host)
-            } else {
-                redis_conn_string = format!("redis://{}/", redis_host)
-            }
+    println!(">>> using log path: {}", config.log.file_path);
+    let log_config = match build_log_config(&config) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("failed to set up logging: {}", e);
+            process::exit(1);
         }
-    }
-    trace!(">> using redis conn string: {}", redis_conn_string);
+    };
+    let log_handle = log4rs::init_config(log_config).unwrap();
+    info!("logger setup completed...");
 
-    let (host, port) = get_host_and_port();
-    let address = format!("{}:{}", host, port);
-    info!("will be listening on: {}", address);
+    trace!(">> using redis conn string: {}", config.redis_conn_string());
+    info!("will be listening on: {}", config.address());
 
-    let with_insecure_auth_service = get_is_insecure_auth_service_arg();
-    if with_insecure_auth_service {
+    if config.insecure {
         warn!("!! running with drunken auth service which lets anyone in");
     }
 
-    let server = Arc::new(Mutex::new(
-        Server::new(redis_conn_string, address, 5, with_insecure_auth_service).unwrap(),
-    ));
+    // geoip country lookup of clicks is only turned on when a database is provided
+    let geoip = match &config.analytics.geoip_db_path {
+        Some(db_path) => match GeoIp::open(db_path) {
+            Ok(g) => {
+                info!("using geoip database: {}", db_path);
+                Some(g)
+            }
+            Err(e) => {
+                eprintln!("failed to open geoip database [{}]: {}", db_path, e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let tracer = match &config.tracing.otlp_endpoint {
+        Some(endpoint) => match Tracer::with_otlp_exporter(endpoint) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("invalid otlp endpoint [{}]: {}", endpoint, e);
+                process::exit(1);
+            }
+        },
+        None => Tracer::disabled(),
+    };
+
+    let server = Arc::new(Mutex::new(Server::new(&config, geoip, tracer).unwrap()));
     // let server_clone = server.clone();
 
+    // on shutdown, /readyz starts failing first, giving load balancers the grace period to
+    // stop sending traffic here
+    let shutdown_grace_secs = config.shutdown_grace_secs;
+    let draining = server.lock().unwrap().draining();
     ctrlc::set_handler(move || {
-        warn!("shutdown initiated ... TODO: not yet fully implemented");
+        warn!(
+            "shutdown initiated, draining for {}s ... TODO: not yet fully implemented",
+            shutdown_grace_secs
+        );
+        draining.store(true, Ordering::Relaxed);
+        thread::sleep(Duration::from_secs(shutdown_grace_secs));
         // server_clone.lock().unwrap().shutdown();
         process::exit(0);
     })
     .expect("error setting ctrl-c handler");
 
+    let router = server.lock().unwrap().router();
+    reload_on_sighup(cli_args, config, log_handle, router);
+
     server.lock().unwrap().start();
 }
 
-fn setup_logger() {
-    let log_file_path;
-    match env::var("LOG_FILE_PATH") {
-        Ok(val) => log_file_path = val,
-        Err(_e) => log_file_path = "log/output.log".to_string(),
-    }
-    println!(">>> using log path: {}", log_file_path);
-
-    let stdout = ConsoleAppender::builder().build();
-    let logfile = FileAppender::builder()
-        .encoder(Box::new(PatternEncoder::new("{d} [{l}]:\t{m}\n")))
-        .build(log_file_path)
-        .unwrap();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .appender(Appender::builder().build("logfile", Box::new(logfile)))
-        .build(
-            Root::builder()
-                .appender("logfile")
-                .appender("stdout")
-                .build(LevelFilter::Trace),
-        )
-        .unwrap();
+// reload_on_sighup reads the config again on SIGHUP, and applies the settings which can be
+// changed while running; an invalid config is rejected, and the current settings are kept
+#[cfg(unix)]
+fn reload_on_sighup(
+    cli_args: CliArgs,
+    config: Config,
+    log_handle: Handle,
+    router: Arc<Mutex<Router>>,
+) {
+    use signal_hook::consts::SIGHUP;
+    use signal_hook::iterator::Signals;
 
-    log4rs::init_config(config).unwrap();
+    let mut signals = Signals::new([SIGHUP]).expect("error setting SIGHUP handler");
+    thread::spawn(move || {
+        let mut current = config;
+        for _ in signals.forever() {
+            info!("SIGHUP received, reloading the configuration ...");
+            let new = match Config::load(&cli_args, |name| env::var(name).ok()) {
+                Ok(c) => c,
+                Err(e) => {
+                    error!("invalid configuration, keeping the current one:\n{}", e);
+                    continue;
+                }
+            };
 
-    info!("logger setup completed...");
-}
+            let reloaded = current.with_reloadable_settings(&new);
+            // only the names, values of the others might be secrets
+            let restart_needed: Vec<String> =
+                reloaded.changes(&new).into_iter().map(|c| c.key).collect();
+            if !restart_needed.is_empty() {
+                warn!(
+                    "changes to [{}] take effect only after a restart",
+                    restart_needed.join(", ")
+                );
+            }
 
-// try to check if "--insecure" program arg is provided, in which case auth service will skip
-// checks for legit (logged in) requests
-fn get_is_insecure_auth_service_arg() -> bool {
-    let args: Vec<String> = env::args().collect();
-    for i in 0..args.len() {
-        if args[i] == "--insecure" {
-            return true;
+            let changes = current.changes(&reloaded);
+            if changes.is_empty() {
+                info!("configuration reloaded, nothing to apply");
+                continue;
+            }
+            if reloaded.log.level != current.log.level {
+                match build_log_config(&reloaded) {
+                    Ok(log_config) => log_handle.set_config(log_config),
+                    Err(e) => {
+                        error!(
+                            "failed to rebuild logging, keeping the current config: {}",
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            router
+                .lock()
+                .unwrap()
+                .apply_settings(&reloaded.links, &reloaded.http);
+            for change in &changes {
+                info!("config reloaded, {}", change);
+            }
+            current = reloaded;
         }
-    }
-    return false;
+    });
 }
 
-// in windows it's annoying to work with env vars, so we need to be able to provide redis
-//  password with program args when developing too
-fn get_redis_pass_arg() -> String {
-    let args: Vec<String> = env::args().collect();
-    for i in 0..args.len() {
-        if args[i] == "-redispass" {
-            return args[i + 1].to_string();
-        }
-    }
-    return String::from("");
+#[cfg(not(unix))]
+fn reload_on_sighup(_: CliArgs, _: Config, _: Handle, _: Arc<Mutex<Router>>) {
+    info!("config reload on SIGHUP is not supported on this platform");
 }
 
-fn get_host_and_port() -> (String, u16) {
-    let mut host = "127.0.0.1";
-    let mut port: u16 = 8080;
+// build_log_config sets up the stdout, log file and access log appenders; failing to open
+// the log files is an error, so a reload can keep the current config instead of panicking
+fn build_log_config(config: &Config) -> Result<LogConfig, String> {
+    // secrets are masked in all log output, the redis password included
+    let redactor = Arc::new(Redactor::new(
+        &config.log.redact_headers,
+        &config.log.redact_query_params,
+        &[
+            config.redis.password.clone(),
+            config.link_cookie_secret.clone(),
+        ],
+    ));
+    // in json, each line is an object, with the request details (request id, method, path,
+    // status, latency, link id) under "mdc"
+    let encoder = || -> Box<dyn Encode> {
+        let encoder: Box<dyn Encode> = match config.log.format.as_str() {
+            "json" => Box::new(JsonEncoder::new()),
+            _ => Box::new(PatternEncoder::new("{d} [{l}]:\t{m}\n")),
+        };
+        Box::new(RedactingEncoder::new(encoder, Arc::clone(&redactor)))
+    };
 
-    let args: Vec<String> = env::args().collect();
-    for i in 0..args.len() {
-        if args[i] == "-host" || args[i] == "-h" {
-            if i + 1 == args.len() {
-                eprintln!("invalid arguments [host], args len: {}", args.len());
-                process::exit(1);
-            }
-            host = args[i + 1].as_str();
-        }
-        if args[i] == "-port" || args[i] == "-p" {
-            if i + 1 == args.len() {
-                eprintln!("invalid arguments [port], args len: {}", args.len());
-                process::exit(1);
-            }
-            match args[i + 1].parse::<u16>() {
-                Ok(n) => port = n,
-                Err(e) => {
-                    eprintln!("invalid port argument: {e}");
-                    process::exit(1);
-                }
-            }
-        }
-    }
+    let stdout = ConsoleAppender::builder().encoder(encoder()).build();
+    let logfile = FileAppender::builder()
+        .encoder(encoder())
+        .build(&config.log.file_path)
+        .map_err(|e| format!("log file [{}]: {}", config.log.file_path, e))?;
+
+    // the access log goes to its own file in the Combined Log Format, rotated once it grows
+    // over the max size, keeping the last few rotated files
+    let access_log_roller = FixedWindowRoller::builder()
+        .build(
+            &format!("{}.{{}}", config.log.access_log_path),
+            ACCESS_LOG_ROTATED_FILES,
+        )
+        .map_err(|e| format!("access log roller: {}", e))?;
+    let access_log_policy = CompoundPolicy::new(
+        Box::new(SizeTrigger::new(
+            config.log.access_log_max_size_mb * 1024 * 1024,
+        )),
+        Box::new(access_log_roller),
+    );
+    let access_log = RollingFileAppender::builder()
+        .encoder(Box::new(RedactingEncoder::new(
+            Box::new(PatternEncoder::new("{m}{n}")),
+            Arc::clone(&redactor),
+        )))
+        .build(&config.log.access_log_path, Box::new(access_log_policy))
+        .map_err(|e| format!("access log [{}]: {}", config.log.access_log_path, e))?;
 
-    (String::from(host), port)
+    LogConfig::builder()
+        .appender(Appender::builder().build("stdout", Box::new(stdout)))
+        .appender(Appender::builder().build("logfile", Box::new(logfile)))
+        .appender(Appender::builder().build("access", Box::new(access_log)))
+        .logger(
+            Logger::builder()
+                .appender("access")
+                .additive(false)
+                .build(ACCESS_LOG_TARGET, LevelFilter::Info),
+        )
+        .build(
+            Root::builder()
+                .appender("logfile")
+                .appender("stdout")
+                .build(config.log_level()),
+        )
+        .map_err(|e| format!("log config: {}", e))
 }
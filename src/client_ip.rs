@@ -0,0 +1,238 @@
+use std::net::{IpAddr, SocketAddr};
+
+// Cidr is a network in CIDR notation, e.g. 10.0.0.0/8 or fd00::/8; a plain ip is
+// treated as a single host network
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Cidr, String> {
+        let cidr = cidr.trim();
+        let (addr, prefix_len) = match cidr.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (cidr, None),
+        };
+
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("invalid network address [{}]: {}", cidr, e))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(p) => p
+                .parse::<u8>()
+                .map_err(|e| format!("invalid prefix length [{}]: {}", cidr, e))?,
+            None => max_len,
+        };
+        if prefix_len > max_len {
+            return Err(format!("prefix length too big [{}]", cidr));
+        }
+
+        Ok(Cidr { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(*ip) & mask
+            }
+            (IpAddr::V4(_), IpAddr::V6(ip)) => match ip.to_ipv4_mapped() {
+                Some(ip) => self.contains(&IpAddr::V4(ip)),
+                None => false,
+            },
+            (IpAddr::V6(_), IpAddr::V4(_)) => false,
+        }
+    }
+}
+
+// TrustedProxies resolves the real client ip of a request; forwarding headers are only
+// looked at when the request comes from one of the trusted proxy networks, otherwise
+// anyone could spoof their ip by setting them
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<Cidr>,
+}
+
+impl TrustedProxies {
+    // parse expects a comma separated list of networks, e.g. "127.0.0.1, 10.0.0.0/8"
+    pub fn parse(networks: &str) -> Result<TrustedProxies, String> {
+        let networks = networks
+            .split(',')
+            .filter(|n| !n.trim().is_empty())
+            .map(Cidr::parse)
+            .collect::<Result<Vec<Cidr>, String>>()?;
+        Ok(TrustedProxies { networks })
+    }
+
+    pub fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.networks.iter().any(|n| n.contains(ip))
+    }
+
+    // client_ip walks the proxy chain from the nearest hop back, and returns the first ip
+    // which is not a trusted proxy; headers are checked in order: Forwarded (RFC 7239),
+    // X-Forwarded-For, X-Real-IP
+    pub fn client_ip(
+        &self,
+        peer_ip: Option<IpAddr>,
+        forwarded: &str,
+        x_forwarded_for: &str,
+        x_real_ip: &str,
+    ) -> Option<IpAddr> {
+        let peer = peer_ip?;
+        if !self.is_trusted(&peer) {
+            return Some(peer);
+        }
+
+        let chain: Vec<Option<IpAddr>> = if !forwarded.is_empty() {
+            parse_forwarded_for(forwarded)
+        } else if !x_forwarded_for.is_empty() {
+            x_forwarded_for.split(',').map(parse_node).collect()
+        } else if !x_real_ip.is_empty() {
+            vec![parse_node(x_real_ip)]
+        } else {
+            return Some(peer);
+        };
+
+        let mut client = peer;
+        for hop in chain.iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = *ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                // unknown or obfuscated node, nothing behind it can be trusted
+                None => break,
+            }
+        }
+
+        Some(client)
+    }
+}
+
+// parse_forwarded_for extracts the "for" nodes from a Forwarded header, e.g.
+// Forwarded: for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8:cafe::17]:4711"
+fn parse_forwarded_for(forwarded: &str) -> Vec<Option<IpAddr>> {
+    forwarded
+        .split(',')
+        .filter_map(|element| {
+            element
+                .split(';')
+                .find_map(|pair| match pair.split_once('=') {
+                    Some((key, value)) if key.trim().eq_ignore_ascii_case("for") => {
+                        Some(parse_node(value))
+                    }
+                    _ => None,
+                })
+        })
+        .collect()
+}
+
+// parse_node parses a single proxy chain node which can be a plain ip, or an ip with
+// port, with ipv6 addresses optionally in brackets and the whole node optionally quoted
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|n| n.strip_suffix(']'))
+        .and_then(|n| n.parse::<IpAddr>().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cidr, TrustedProxies};
+    use std::net::IpAddr;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr = Cidr::parse("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.1.2.3")));
+        assert!(cidr.contains(&ip("::ffff:10.1.2.3")));
+
+        let cidr = Cidr::parse("127.0.0.1").unwrap();
+        assert!(cidr.contains(&ip("127.0.0.1")));
+        assert!(!cidr.contains(&ip("127.0.0.2")));
+
+        let cidr = Cidr::parse("fd00::/8").unwrap();
+        assert!(cidr.contains(&ip("fd12::1")));
+        assert!(!cidr.contains(&ip("2001:db8::1")));
+        assert!(!cidr.contains(&ip("10.1.2.3")));
+
+        let cidr = Cidr::parse("0.0.0.0/0").unwrap();
+        assert!(cidr.contains(&ip("203.0.113.7")));
+
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("10.0.0/8").is_err());
+        assert!(Cidr::parse("10.0.0.0/x").is_err());
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let proxies = TrustedProxies::parse("127.0.0.1, 10.0.0.0/8").unwrap();
+        let proxy = Some(ip("10.0.0.1"));
+
+        // untrusted peers are never allowed to set forwarding headers
+        let stranger = Some(ip("198.51.100.1"));
+        assert_eq!(
+            proxies.client_ip(stranger, "", "203.0.113.7", "203.0.113.8"),
+            stranger
+        );
+
+        // no forwarding headers at all
+        assert_eq!(proxies.client_ip(proxy, "", "", ""), proxy);
+
+        assert_eq!(
+            proxies.client_ip(proxy, "", "203.0.113.7, 10.0.0.2", ""),
+            Some(ip("203.0.113.7"))
+        );
+        // the leftmost entry is spoofable, the first untrusted one from the right wins
+        assert_eq!(
+            proxies.client_ip(proxy, "", "1.1.1.1, 203.0.113.7, 10.0.0.2", ""),
+            Some(ip("203.0.113.7"))
+        );
+        assert_eq!(
+            proxies.client_ip(proxy, "", "", "203.0.113.8"),
+            Some(ip("203.0.113.8"))
+        );
+        assert_eq!(
+            proxies.client_ip(
+                proxy,
+                r#"for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8:cafe::17]:4711""#,
+                "203.0.113.7",
+                ""
+            ),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(
+            proxies.client_ip(proxy, "for=192.0.2.43:47011, for=10.0.0.2", "", ""),
+            Some(ip("192.0.2.43"))
+        );
+        assert_eq!(
+            proxies.client_ip(proxy, "for=unknown, for=10.0.0.2", "", ""),
+            Some(ip("10.0.0.2"))
+        );
+        assert_eq!(proxies.client_ip(None, "", "203.0.113.7", ""), None);
+    }
+}
@@ -1,23 +1,41 @@
+use chrono::Utc;
 use http::StatusCode;
 use log::debug;
 use redis::{Commands, Connection, RedisError};
 use std::net::TcpStream;
+use std::sync::Arc;
 
 extern crate redis;
-use crate::handlers::Handlers;
+use crate::{
+    alias_handler::alias_key,
+    analytics,
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    metrics::Metrics,
+    telemetry,
+    trash::TRASH_SET,
+    url_record::URLRecord,
+};
 
 pub struct DeleteHandler {
     redis_conn: Connection,
+    metrics: Arc<Metrics>,
 }
 
 impl DeleteHandler {
-    pub fn new(redis_conn_string: &String) -> Result<DeleteHandler, RedisError> {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<DeleteHandler, RedisError> {
         let redis_client = redis::Client::open(String::from(redis_conn_string))?;
         let redis_conn = redis_client.get_connection()?;
-        Ok(DeleteHandler { redis_conn })
+        Ok(DeleteHandler {
+            redis_conn,
+            metrics,
+        })
     }
 
-    pub fn handle_delete(&mut self, stream: TcpStream, path: &str) {
+    pub fn handle_delete(&mut self, stream: TcpStream, path: &str, actor: &str) {
         debug!("will delete url: {}", path);
 
         let path_parts = path.split("?");
@@ -43,7 +61,7 @@ impl DeleteHandler {
         }
 
         let id = id_parts_vec[1];
-        if id == "" {
+        if id.is_empty() {
             Handlers::respond_with_status_code(
                 stream,
                 StatusCode::BAD_REQUEST.as_u16(),
@@ -52,32 +70,183 @@ impl DeleteHandler {
             return;
         }
 
-        debug!(">>> will be deleting url: {}", id);
-        let url_key = format!("short_url::{}", id);
-        let del_res: i32 = self
-            .redis_conn
-            .del(&url_key)
-            .expect("failed to delete url by key");
+        log_mdc::insert("link_id", id);
 
-        let log_msg = format!("delete [{}] result: {}", id, del_res);
-        debug!(">>> {}", log_msg);
+        // deleting an alias leaves its link in place
+        match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<String, Option<String>>(alias_key(id))
+        }) {
+            Ok(Some(link_id)) => {
+                self.delete_alias(stream, id, &link_id, actor);
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                debug!("failed to execute GET for alias [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        }
+
+        debug!(">>> will be moving url to the trash: {}", id);
+        let url_key = format!("short_url::{}", id);
+        let mut url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("url [{}] not found", id),
+                );
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        if url_record.deleted_at.is_some() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::CONFLICT.as_u16(),
+                format!("url [{}] is already in the trash", id),
+            );
+            return;
+        }
 
-        if del_res == 0 {
+        // the link, its aliases, hits and history are kept until the trash is purged
+        if let Err(err) = history::record_baseline(&mut self.redis_conn, &mut url_record) {
+            debug!("failed to record the history baseline of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+        url_record.deleted_at = Some(Utc::now().timestamp());
+        url_record.version += 1;
+        let res: Result<(), _> = telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, url_record.to_json())
+        });
+        if let Err(err) = res {
+            debug!("failed to execute SET for [{}]: {}", url_key, err);
+            self.metrics.inc_redis_errors();
             Handlers::respond_with_status_code(
                 stream,
-                StatusCode::NOT_FOUND.as_u16(),
-                String::from(log_msg),
+                StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                err.to_string(),
             );
             return;
         }
 
-        // now remove the key from the short_urls set
-        let del_res: i32 = self
-            .redis_conn
-            .srem("short_urls", &url_key)
-            .expect("failed to delete url key [{}] from the short_urls set");
-        debug!("delete {} from short_urls set result: {}", url_key, del_res);
+        // now move the key from the short_urls set to the trash
+        match telemetry::traced("redis SREM", || {
+            self.redis_conn.srem::<_, _, i32>("short_urls", &url_key)
+        }) {
+            Ok(del_res) => debug!("delete {} from short_urls set result: {}", url_key, del_res),
+            Err(err) => {
+                debug!("failed to delete {} from short_urls set: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+            }
+        }
+        let res: Result<i32, _> =
+            telemetry::traced("redis SADD", || self.redis_conn.sadd(TRASH_SET, &url_key));
+        if let Err(err) = res {
+            debug!("failed to add {} to the trash set: {}", url_key, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        let entry = HistoryEntry::new(&url_record, actor, "delete");
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the deletion of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        let log_msg = format!("url [{}] moved to the trash", id);
+        debug!(">>> {}", log_msg);
+        Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), log_msg);
+    }
+
+    // delete_alias removes the alias from its link, the clicks made through it stay in the
+    // hits of the link
+    fn delete_alias(&mut self, stream: TcpStream, alias: &str, link_id: &str, actor: &str) {
+        debug!(">>> will be deleting alias [{}] of url: {}", alias, link_id);
+        let del_res: i32 =
+            match telemetry::traced("redis DEL", || self.redis_conn.del(alias_key(alias))) {
+                Ok(val) => val,
+                Err(err) => {
+                    debug!("failed to execute DEL for alias [{}]: {}", alias, err);
+                    self.metrics.inc_redis_errors();
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err.to_string(),
+                    );
+                    return;
+                }
+            };
+
+        let url_key = format!("short_url::{}", link_id);
+        match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => {
+                let mut url_record = URLRecord::from_json(link_id.to_string(), &url_record);
+                if let Err(err) = history::record_baseline(&mut self.redis_conn, &mut url_record) {
+                    debug!(
+                        "failed to record the history baseline of [{}]: {}",
+                        link_id, err
+                    );
+                    self.metrics.inc_redis_errors();
+                }
+                url_record.aliases.retain(|a| a != alias);
+                url_record.version += 1;
+                let res: Result<(), _> = telemetry::traced("redis SET", || {
+                    self.redis_conn.set(&url_key, url_record.to_json())
+                });
+                if let Err(err) = res {
+                    debug!("failed to execute SET for [{}]: {}", url_key, err);
+                    self.metrics.inc_redis_errors();
+                } else {
+                    let entry = HistoryEntry::new(&url_record, actor, "remove_alias");
+                    if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+                        debug!(
+                            "failed to record the removed alias of [{}]: {}",
+                            link_id, err
+                        );
+                        self.metrics.inc_redis_errors();
+                    }
+                }
+            }
+            Ok(None) => debug!("url [{}] of alias [{}] is gone", link_id, alias),
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+            }
+        }
+
+        let res: Result<i32, _> = telemetry::traced("redis HDEL", || {
+            self.redis_conn.hdel(analytics::aliases_key(link_id), alias)
+        });
+        if let Err(err) = res {
+            debug!("failed to drop the hits of alias [{}]: {}", alias, err);
+            self.metrics.inc_redis_errors();
+        }
 
-        Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), String::from(log_msg));
+        let log_msg = format!(
+            "delete alias [{}] of [{}] result: {}",
+            alias, link_id, del_res
+        );
+        debug!(">>> {}", log_msg);
+        Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), log_msg);
     }
 }
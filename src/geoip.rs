@@ -0,0 +1,36 @@
+use log::debug;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::net::IpAddr;
+
+// GeoIp resolves client IPs to ISO country codes using a local MaxMind-format (.mmdb)
+// database, so no network lookups are made while serving redirects
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+impl GeoIp {
+    pub fn open(db_path: &str) -> Result<GeoIp, MaxMindDBError> {
+        let reader = Reader::open_readfile(db_path)?;
+        debug!(
+            "geoip database loaded: {} (built at {})",
+            reader.metadata.database_type, reader.metadata.build_epoch
+        );
+        Ok(GeoIp { reader })
+    }
+
+    pub fn country_code(&self, ip: IpAddr) -> Option<String> {
+        let country: geoip2::Country = match self.reader.lookup(ip) {
+            Ok(c) => c,
+            Err(e) => {
+                debug!("geoip lookup for [{}] failed: {}", ip, e);
+                return None;
+            }
+        };
+
+        country
+            .country
+            .or(country.registered_country)
+            .and_then(|c| c.iso_code)
+            .map(String::from)
+    }
+}
@@ -1,44 +1,59 @@
 use http::StatusCode;
 use log::{debug, warn};
 use redis::{Commands, Connection, RedisError};
-use std::{collections::HashSet, net::TcpStream};
+use std::{collections::HashSet, net::TcpStream, sync::Arc};
+use url::form_urlencoded;
 
 extern crate redis;
 
-use crate::{handlers::Handlers, url_record::URLRecord};
+use crate::{handlers::Handlers, metrics::Metrics, telemetry, url_record::URLRecord};
 
 pub struct GetAllHandler {
     redis_conn: Connection,
+    metrics: Arc<Metrics>,
 }
 
 impl GetAllHandler {
-    pub fn new(redis_conn_string: &String) -> Result<GetAllHandler, RedisError> {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<GetAllHandler, RedisError> {
         let redis_client = redis::Client::open(String::from(redis_conn_string))?;
         let redis_conn = redis_client.get_connection()?;
-        Ok(GetAllHandler { redis_conn })
+        Ok(GetAllHandler {
+            redis_conn,
+            metrics,
+        })
     }
 
-    pub fn handle_get_all(&mut self, stream: TcpStream) {
+    // handle_get_all returns all the links, or only the ones of a campaign, given as:
+    // /all?campaign=<utm campaign>
+    pub fn handle_get_all(&mut self, stream: TcpStream, path: &str) {
         debug!("trying to find and return all links ...");
+        let campaign = get_campaign_param(path);
 
         // get all link ids
-        let url_keys: HashSet<String> = match self.redis_conn.smembers("short_urls") {
-            Ok(uk) => uk,
-            Err(err) => {
-                debug!("failed to execute SMEMBERS for 'short_urls': {}", err);
-                Handlers::respond_with_status_code(
-                    stream,
-                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
-                    err.to_string(),
-                );
-                return;
-            }
-        };
+        let url_keys: HashSet<String> =
+            match telemetry::traced("redis SMEMBERS", || self.redis_conn.smembers("short_urls")) {
+                Ok(uk) => uk,
+                Err(err) => {
+                    debug!("failed to execute SMEMBERS for 'short_urls': {}", err);
+                    self.metrics.inc_redis_errors();
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err.to_string(),
+                    );
+                    return;
+                }
+            };
 
         let mut url_records = vec![];
 
         for url_key in &url_keys {
-            match self.redis_conn.get::<&String, String>(&url_key) {
+            match telemetry::traced("redis GET", || {
+                self.redis_conn.get::<&String, String>(url_key)
+            }) {
                 Ok(url_record) => {
                     // url key is created as: format!("short_url::{}", new_id);
                     let url_id = url_key.split("::");
@@ -50,10 +65,14 @@ impl GetAllHandler {
                     let url_id = url_id[1];
 
                     let url_record = URLRecord::from_json(url_id.to_string(), &url_record);
-                    url_records.push(url_record);
+                    if campaign.is_some() && get_campaign(&url_record) != campaign.as_deref() {
+                        continue;
+                    }
+                    url_records.push(url_record.to_public_value());
                 }
                 Err(e) => {
-                    debug!("error reading URL by key [{}]: {}", &url_key, e)
+                    debug!("error reading URL by key [{}]: {}", &url_key, e);
+                    self.metrics.inc_redis_errors();
                 }
             }
         }
@@ -63,3 +82,34 @@ impl GetAllHandler {
         Handlers::json_response(stream, StatusCode::OK.as_u16(), res_json);
     }
 }
+
+fn get_campaign(url_record: &URLRecord) -> Option<&str> {
+    url_record.utm.as_ref()?.campaign.as_deref()
+}
+
+fn get_campaign_param(path: &str) -> Option<String> {
+    let (_, query) = path.split_once('?')?;
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(name, _)| name == "campaign")
+        .map(|(_, value)| value.into_owned())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_campaign_param;
+
+    #[test]
+    fn test_get_campaign_param() {
+        assert_eq!(get_campaign_param("/all"), None);
+        assert_eq!(
+            get_campaign_param("/all?campaign=spring%20sale"),
+            Some("spring sale".to_string())
+        );
+        assert_eq!(
+            get_campaign_param("/all?x=1&campaign=spring+sale"),
+            Some("spring sale".to_string())
+        );
+        assert_eq!(get_campaign_param("/all?campaign="), None);
+    }
+}
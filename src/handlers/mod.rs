@@ -1,144 +1,404 @@
+use http::StatusCode;
 use log::{debug, error};
+use std::cell::{Cell, RefCell};
 use std::io::Write;
 use std::net::TcpStream;
 
+// ResponseInfo describes the response which was written to the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResponseInfo {
+    pub status: u16,
+    // size of the response body
+    pub bytes: usize,
+}
+
+thread_local! {
+    // a request is handled on a single worker thread from start to end, so the router can
+    // pick up what was written by the handlers once it's done with the request
+    static LAST_RESPONSE: Cell<Option<ResponseInfo>> = const { Cell::new(None) };
+    // and tell the handlers which request id to echo back
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    // and which origin browsers may read the responses from, none if it's not allowed
+    static ALLOWED_ORIGIN: RefCell<Option<String>> = const { RefCell::new(None) };
+    // and whether the response body is left out, as for HEAD requests
+    static HEAD_REQUEST: Cell<bool> = const { Cell::new(false) };
+}
+
 pub struct Handlers {}
 
 impl Handlers {
-    pub fn handle_redirect(mut stream: TcpStream, url: String) {
-        let content = r#"<html>
-<head>
-    <title>Moved</title>
-</head>
-<body>
-    =Moved=
-    <p>This page has moved.</p>
-</body>
-</html>
-"#;
-        let content_len = content.len();
-        let response = format!(
-            r#"HTTP/1.1 301 Moved Permanently
-content-type: text/html; charset=UTF-8
-Content-Length: {content_len}
-Location: {url}
-Content-Type: text/html
+    // take_last_response returns the info of the last response sent from this thread
+    pub fn take_last_response() -> Option<ResponseInfo> {
+        LAST_RESPONSE.with(|r| r.take())
+    }
 
-{content}"#
-        );
+    // last_response is like take_last_response, but leaves the info in place
+    pub fn last_response() -> Option<ResponseInfo> {
+        LAST_RESPONSE.with(|r| r.get())
+    }
+
+    // set_request_id makes all responses sent from this thread carry the X-Request-Id header
+    pub fn set_request_id(request_id: Option<String>) {
+        REQUEST_ID.with(|r| r.replace(request_id));
+    }
+
+    // set_allowed_origin sets the Access-Control-Allow-Origin of all responses sent from this
+    // thread, which is left out with none
+    pub fn set_allowed_origin(origin: Option<String>) {
+        ALLOWED_ORIGIN.with(|o| o.replace(origin));
+    }
+
+    // set_head_request makes the responses sent from this thread go without their body, the
+    // headers (Content-Length included) stay as they would be for GET
+    pub fn set_head_request(is_head: bool) {
+        HEAD_REQUEST.with(|h| h.set(is_head));
+    }
 
-        match stream.write(response.as_bytes()) {
-            Ok(_) => debug!("redirect response sent: {}", response),
-            Err(e) => error!("failed sending redirect response: {}", e),
+    fn send(mut stream: TcpStream, response: &[u8], name: &str) {
+        let response =
+            ALLOWED_ORIGIN.with(|o| with_allowed_origin(response, o.borrow().as_deref()));
+        let response = match REQUEST_ID.with(|r| r.borrow().clone()) {
+            Some(request_id) => with_header(&response, "X-Request-Id", &request_id),
+            None => response,
+        };
+        let mut response = response.as_slice();
+        if HEAD_REQUEST.with(|h| h.get()) {
+            response = without_body(response);
+        }
+
+        LAST_RESPONSE.with(|r| {
+            r.set(Some(ResponseInfo {
+                status: get_response_status(response),
+                bytes: get_body_len(response),
+            }))
+        });
+
+        match stream.write_all(response) {
+            Ok(_) => debug!("response [{}] sent", name),
+            Err(e) => error!("failed sending response [{}]: {}", name, e),
         }
         match stream.flush() {
-            Ok(_) => debug!("response [redirect] flushed"),
-            Err(e) => error!("failed flushing response [redirect]: {}", e),
+            Ok(_) => debug!("response [{}] flushed", name),
+            Err(e) => error!("failed flushing response [{}]: {}", name, e),
         }
     }
 
-    pub fn respond_with_status_code(mut stream: TcpStream, code: u16, message: String) {
+    pub fn handle_redirect(
+        stream: TcpStream,
+        status: StatusCode,
+        url: String,
+        headers: &[(&str, String)],
+    ) {
+        let content = "<html>\r\n<head>\r\n    <title>Moved</title>\r\n</head>\r\n<body>\r\n    =Moved=\r\n    <p>This page has moved.</p>\r\n</body>\r\n</html>\r\n";
+        let content_len = content.len();
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
         let response = format!(
-            "HTTP/1.1 {code}\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n{message}\r\n"
+            "HTTP/1.1 {status}\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {content_len}\r\nLocation: {url}\r\n{headers}\r\n{content}"
         );
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => debug!("response sent"),
-            Err(e) => error!("failed sending response: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response flushed"),
-            Err(e) => error!("failed flushing json response: {}", e),
-        }
+
+        debug!("sending redirect response: {}", response);
+        Handlers::send(stream, response.as_bytes(), "redirect");
     }
 
-    pub fn respond_options_ok(mut stream: TcpStream, path: &str, allowed_method: &str) {
-        let response = String::from(
-            format!("HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: {},OPTIONS\r\nAccess-Control-Allow-Headers: *\r\n\r\n<html><body>OK</body></html>\r\n", allowed_method)
+    pub fn respond_with_status_code(stream: TcpStream, code: u16, message: String) {
+        let response = format!(
+            "HTTP/1.1 {code}\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n{message}\r\n"
         );
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => debug!("OPTIONS response sent for path: {}", path),
-            Err(e) => error!("failed sending OPTIONS response: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("OPTIONS response flushed"),
-            Err(e) => error!("failed flushing OPTIONS response: {}", e),
-        }
+        Handlers::send(stream, response.as_bytes(), "status code");
     }
 
-    pub fn json_response(mut stream: TcpStream, code: u16, data: String) {
+    pub fn respond_options_ok(stream: TcpStream, path: &str, allowed_method: &str) {
+        let response = format!("HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nAccess-Control-Allow-Methods: {},OPTIONS\r\nAccess-Control-Allow-Headers: *\r\n\r\n<html><body>OK</body></html>\r\n", allowed_method);
+        debug!("sending OPTIONS response for path: {}", path);
+        Handlers::send(stream, response.as_bytes(), "options");
+    }
+
+    pub fn json_response(stream: TcpStream, code: u16, data: String) {
         let response = format!(
             "HTTP/1.1 {code}\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{data}\r\n"
         );
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => debug!("json response sent"),
-            Err(e) => error!("failed sending json response: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response flushed"),
-            Err(e) => error!("failed flushing json response: {}", e),
-        }
+        Handlers::send(stream, response.as_bytes(), "json");
+    }
+
+    pub fn html_response(stream: TcpStream, code: u16, headers: &[(&str, String)], html: String) {
+        let content_len = html.len();
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        let response = format!(
+            "HTTP/1.1 {code}\r\nContent-Type: text/html; charset=UTF-8\r\nContent-Length: {content_len}\r\n{headers}\r\n{html}"
+        );
+        Handlers::send(stream, response.as_bytes(), "html");
+    }
+
+    pub fn binary_response(
+        stream: TcpStream,
+        code: u16,
+        content_type: &str,
+        headers: &[(&str, String)],
+        data: &[u8],
+    ) {
+        let content_len = data.len();
+        let headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}: {}\r\n", name, value))
+            .collect();
+        let mut response = format!(
+            "HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {content_len}\r\n{headers}\r\n"
+        )
+        .into_bytes();
+        response.extend_from_slice(data);
+        Handlers::send(stream, &response, "binary");
     }
 
-    pub fn handle_hello_world(mut stream: TcpStream) {
+    pub fn text_response(stream: TcpStream, code: u16, content_type: &str, data: String) {
+        let content_len = data.len();
+        let response = format!(
+            "HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {content_len}\r\n\r\n{data}"
+        );
+        Handlers::send(stream, response.as_bytes(), "text");
+    }
+
+    pub fn handle_hello_world(stream: TcpStream) {
         let response = b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\n<html><body>Hello world budy!</body></html>\r\n";
-        match stream.write_all(response) {
-            Ok(_) => debug!("response sent"),
-            Err(e) => error!("failed sending response: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response flushed"),
-            Err(e) => error!("failed flushing json response: {}", e),
-        }
+        Handlers::send(stream, response, "hello world");
     }
 
-    pub fn handle_ping(mut stream: TcpStream) {
+    pub fn handle_ping(stream: TcpStream) {
         let response =
             b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\nPong!\r\n";
-        match stream.write_all(response) {
-            Ok(_) => debug!("response [ping] sent"),
-            Err(e) => error!("failed sending response [ping]: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response [ping] flushed"),
-            Err(e) => error!("failed flushing response [ping]: {}", e),
-        }
+        Handlers::send(stream, response, "ping");
     }
 
-    pub fn handle_unknown_path(mut stream: TcpStream) {
+    pub fn handle_unknown_path(stream: TcpStream) {
         let response =
             b"HTTP/1.1 404\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\nNot Found :(\r\n";
-        match stream.write_all(response) {
-            Ok(_) => debug!("response [unknown path] sent"),
-            Err(e) => error!("failed sending response [unknown path]: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response flushed"),
-            Err(e) => error!("failed flushing json response: {}", e),
-        }
+        Handlers::send(stream, response, "unknown path");
     }
 
-    pub fn handle_method_not_allowed(mut stream: TcpStream, method: &str) {
+    pub fn handle_method_not_allowed(stream: TcpStream, method: &str) {
         let message = format!("HTTP/1.1 405\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\nMethod {} not allowed\r\n", method);
-        let response = message.as_bytes();
-        match stream.write_all(response) {
-            Ok(_) => debug!("response [unknown path] sent"),
-            Err(e) => error!("failed sending response [unknown path]: {}", e),
-        }
-        match stream.flush() {
-            Ok(_) => debug!("response flushed"),
-            Err(e) => error!("failed flushing json response: {}", e),
-        }
+        Handlers::send(stream, message.as_bytes(), "method not allowed");
     }
 
-    pub fn handle_unauthorized(mut stream: TcpStream) {
+    pub fn handle_unauthorized(stream: TcpStream) {
         let message = b"HTTP/1.1 401 Unauthorized\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html; charset=UTF-8\r\n\r\nUnauthorized\r\n";
-        match stream.write_all(message) {
-            Ok(_) => debug!("response [unauthorized] sent"),
-            Err(e) => error!("failed sending response [unauthorized]: {}", e),
+        Handlers::send(stream, message, "unauthorized");
+    }
+
+    pub fn handle_too_many_requests(stream: TcpStream, retry_after_secs: u64) {
+        let message = format!("HTTP/1.1 429 Too Many Requests\r\nAccess-Control-Allow-Origin: *\r\nRetry-After: {}\r\nContent-Type: text/html; charset=UTF-8\r\n\r\nToo many requests\r\n", retry_after_secs);
+        Handlers::send(stream, message.as_bytes(), "too many requests");
+    }
+}
+
+// escape_html makes text safe to put in html, attribute values included
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
         }
-        match stream.flush() {
-            Ok(_) => debug!("response [unauthorized] flushed"),
-            Err(e) => error!("failed flushing json [unauthorized] response: {}", e),
+    }
+    escaped
+}
+
+// with_header adds a header right after the status line of the response
+fn with_header(response: &[u8], name: &str, value: &str) -> Vec<u8> {
+    let status_line_end = match response.iter().position(|b| *b == b'\n') {
+        Some(i) => i + 1,
+        None => return response.to_vec(),
+    };
+    // keep the line endings already used by the response
+    let line_ending = if response[..status_line_end].ends_with(b"\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+
+    let mut out = Vec::with_capacity(response.len() + name.len() + value.len() + 4);
+    out.extend_from_slice(&response[..status_line_end]);
+    out.extend_from_slice(format!("{}: {}{}", name, value, line_ending).as_bytes());
+    out.extend_from_slice(&response[status_line_end..]);
+    out
+}
+
+// with_allowed_origin replaces the allowed origin of the response, or drops the header when no
+// origin is allowed; a specific origin makes the response vary by the Origin header
+fn with_allowed_origin(response: &[u8], origin: Option<&str>) -> Vec<u8> {
+    const ALLOW_ORIGIN: &[u8] = b"Access-Control-Allow-Origin: *";
+    let start = match response
+        .windows(ALLOW_ORIGIN.len())
+        .position(|w| w == ALLOW_ORIGIN)
+    {
+        Some(i) => i,
+        None => return response.to_vec(),
+    };
+    let end = start + ALLOW_ORIGIN.len();
+    let line_ending: &[u8] = if response[end..].starts_with(b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+
+    let mut out = Vec::with_capacity(response.len() + 64);
+    out.extend_from_slice(&response[..start]);
+    match origin {
+        Some("*") => out.extend_from_slice(ALLOW_ORIGIN),
+        Some(origin) => {
+            out.extend_from_slice(format!("Access-Control-Allow-Origin: {}", origin).as_bytes());
+            out.extend_from_slice(line_ending);
+            out.extend_from_slice(b"Vary: Origin");
+        }
+        None => {
+            // the whole line goes
+            out.extend_from_slice(&response[(end + line_ending.len()).min(response.len())..]);
+            return out;
         }
     }
+    out.extend_from_slice(&response[end..]);
+    out
+}
+
+// without_body returns the status line and the headers of the response
+fn without_body(response: &[u8]) -> &[u8] {
+    &response[..response.len() - get_body_len(response)]
+}
+
+// get_body_len returns the size of what follows the headers of the response
+fn get_body_len(response: &[u8]) -> usize {
+    let crlf = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map(|i| i + 4);
+    let lf = response
+        .windows(2)
+        .position(|w| w == b"\n\n")
+        .map(|i| i + 2);
+    match (crlf, lf) {
+        (Some(a), Some(b)) => response.len() - a.min(b),
+        (Some(i), None) | (None, Some(i)) => response.len() - i,
+        (None, None) => 0,
+    }
+}
+
+// get_response_status reads the status code from the status line, e.g. HTTP/1.1 301 Moved
+fn get_response_status(response: &[u8]) -> u16 {
+    let status_line = match response.split(|b| *b == b'\n').next() {
+        Some(line) => String::from_utf8_lossy(line),
+        None => return 0,
+    };
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        escape_html, get_body_len, get_response_status, with_allowed_origin, with_header,
+        without_body,
+    };
+
+    #[test]
+    fn test_escape_html() {
+        assert_eq!(
+            escape_html(r#"<a href="x">'&'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+        assert_eq!(escape_html("https://2beens.xyz"), "https://2beens.xyz");
+    }
+
+    #[test]
+    fn test_get_response_status() {
+        assert_eq!(
+            get_response_status(b"HTTP/1.1 301 Moved Permanently\ncontent-type: text/html"),
+            301
+        );
+        assert_eq!(
+            get_response_status(b"HTTP/1.1 404\r\n\r\nNot Found :(\r\n"),
+            404
+        );
+        assert_eq!(get_response_status(b"HTTP/1.1 200 OK"), 200);
+        assert_eq!(get_response_status(b""), 0);
+        assert_eq!(get_response_status(b"garbage"), 0);
+    }
+
+    #[test]
+    fn test_get_body_len() {
+        assert_eq!(
+            get_body_len(b"HTTP/1.1 404\r\nA: b\r\n\r\nNot Found :(\r\n"),
+            14
+        );
+        assert_eq!(
+            get_body_len(b"HTTP/1.1 301 Moved\nLocation: x\n\n<html>"),
+            6
+        );
+        assert_eq!(get_body_len(b"HTTP/1.1 200 OK\r\n\r\n"), 0);
+        assert_eq!(get_body_len(b"garbage"), 0);
+    }
+
+    #[test]
+    fn test_with_header() {
+        assert_eq!(
+            with_header(
+                b"HTTP/1.1 404\r\n\r\nNot Found :(\r\n",
+                "X-Request-Id",
+                "abc"
+            ),
+            b"HTTP/1.1 404\r\nX-Request-Id: abc\r\n\r\nNot Found :(\r\n"
+        );
+        assert_eq!(
+            with_header(
+                b"HTTP/1.1 301 Moved\nLocation: x\n\n",
+                "X-Request-Id",
+                "abc"
+            ),
+            b"HTTP/1.1 301 Moved\nX-Request-Id: abc\nLocation: x\n\n"
+        );
+        assert_eq!(with_header(b"garbage", "X-Request-Id", "abc"), b"garbage");
+    }
+
+    #[test]
+    fn test_with_allowed_origin() {
+        let response = b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: *\r\nContent-Type: text/html\r\n\r\nPong!\r\n";
+        assert_eq!(with_allowed_origin(response, Some("*")), response);
+        assert_eq!(
+            with_allowed_origin(response, Some("https://app.example.com")),
+            b"HTTP/1.1 200 OK\r\nAccess-Control-Allow-Origin: https://app.example.com\r\nVary: Origin\r\nContent-Type: text/html\r\n\r\nPong!\r\n"
+        );
+        assert_eq!(
+            with_allowed_origin(response, None),
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\nPong!\r\n"
+        );
+        assert_eq!(
+            with_allowed_origin(b"HTTP/1.1 200 OK\r\n\r\n", None),
+            b"HTTP/1.1 200 OK\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn test_without_body() {
+        assert_eq!(
+            without_body(
+                b"HTTP/1.1 301 Moved Permanently\r\nContent-Length: 6\r\nLocation: x\r\n\r\n<html>"
+            ),
+            b"HTTP/1.1 301 Moved Permanently\r\nContent-Length: 6\r\nLocation: x\r\n\r\n"
+        );
+        assert_eq!(
+            without_body(b"HTTP/1.1 404\r\n\r\n"),
+            b"HTTP/1.1 404\r\n\r\n"
+        );
+    }
 }
@@ -0,0 +1,198 @@
+use http::StatusCode;
+use log::debug;
+use redis::{Connection, RedisError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+extern crate redis;
+use crate::{handlers::Handlers, telemetry, thread_pool::PoolStats};
+
+// how long a storage round trip may take for the instance to still be considered ready
+pub const STORAGE_LATENCY_BUDGET: Duration = Duration::from_millis(250);
+
+const STATUS_OK: &str = "ok";
+const STATUS_FAIL: &str = "fail";
+
+#[derive(Serialize, Debug, PartialEq)]
+struct CheckResult {
+    status: &'static str,
+    message: String,
+}
+
+impl CheckResult {
+    fn ok(message: String) -> CheckResult {
+        CheckResult {
+            status: STATUS_OK,
+            message,
+        }
+    }
+
+    fn fail(message: String) -> CheckResult {
+        CheckResult {
+            status: STATUS_FAIL,
+            message,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    status: &'static str,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+// HealthHandler tells whether the process is alive, and whether it's ready to take traffic;
+// it keeps its own storage connection, which is re-established after failures
+pub struct HealthHandler {
+    redis_conn_string: String,
+    redis_conn: Option<Connection>,
+    latency_budget: Duration,
+}
+
+impl HealthHandler {
+    pub fn new(redis_conn_string: &str, latency_budget: Duration) -> HealthHandler {
+        HealthHandler {
+            redis_conn_string: redis_conn_string.to_string(),
+            redis_conn: None,
+            latency_budget,
+        }
+    }
+
+    // handle_healthz responds as long as the process is able to handle requests at all
+    pub fn handle_healthz(&self, stream: TcpStream) {
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            String::from(r#"{"status":"ok"}"#),
+        );
+    }
+
+    // handle_readyz checks the storage, the thread pool load and whether a shutdown is in
+    // progress, responding with 503 if any of the checks fails
+    pub fn handle_readyz(
+        &mut self,
+        stream: TcpStream,
+        pool_stats: Option<&PoolStats>,
+        draining: bool,
+    ) {
+        let mut checks = BTreeMap::new();
+        checks.insert("storage", self.check_storage());
+        if let Some(pool_stats) = pool_stats {
+            checks.insert(
+                "thread_pool",
+                check_thread_pool(pool_stats.size(), pool_stats.queued(), pool_stats.busy()),
+            );
+        }
+        checks.insert("draining", check_draining(draining));
+
+        let readiness = get_readiness(checks);
+        let status_code = if readiness.status == "ready" {
+            StatusCode::OK
+        } else {
+            debug!("instance not ready: {:?}", readiness.checks);
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        Handlers::json_response(
+            stream,
+            status_code.as_u16(),
+            serde_json::to_string(&readiness).unwrap(),
+        );
+    }
+
+    fn check_storage(&mut self) -> CheckResult {
+        let started_at = Instant::now();
+        let res = telemetry::traced("redis PING", || self.ping());
+        let latency = started_at.elapsed();
+
+        match res {
+            Ok(_) if latency > self.latency_budget => CheckResult::fail(format!(
+                "ping took {:.1}ms, over the budget of {}ms",
+                latency.as_secs_f64() * 1000.0,
+                self.latency_budget.as_millis()
+            )),
+            Ok(_) => CheckResult::ok(format!("ping took {:.1}ms", latency.as_secs_f64() * 1000.0)),
+            Err(err) => {
+                // the connection might be broken for good, start over with the next check
+                self.redis_conn = None;
+                CheckResult::fail(format!("ping failed: {}", err))
+            }
+        }
+    }
+
+    fn ping(&mut self) -> Result<String, RedisError> {
+        let redis_conn = match &mut self.redis_conn {
+            Some(redis_conn) => redis_conn,
+            None => {
+                let redis_client = redis::Client::open(self.redis_conn_string.as_str())?;
+                let redis_conn = redis_client.get_connection_with_timeout(self.latency_budget)?;
+                redis_conn.set_read_timeout(Some(self.latency_budget))?;
+                redis_conn.set_write_timeout(Some(self.latency_budget))?;
+                self.redis_conn.insert(redis_conn)
+            }
+        };
+        redis::cmd("PING").query(redis_conn)
+    }
+}
+
+// check_thread_pool fails once there are more requests waiting for a worker than there are
+// workers, the instance can't keep up at that point
+fn check_thread_pool(size: usize, queued: usize, busy: usize) -> CheckResult {
+    let message = format!("{} of {} workers busy, {} queued", busy, size, queued);
+    if queued > size {
+        return CheckResult::fail(message);
+    }
+    CheckResult::ok(message)
+}
+
+fn check_draining(draining: bool) -> CheckResult {
+    if draining {
+        return CheckResult::fail(String::from("draining for shutdown"));
+    }
+    CheckResult::ok(String::from("accepting traffic"))
+}
+
+fn get_readiness(checks: BTreeMap<&'static str, CheckResult>) -> Readiness {
+    let status = if checks.values().all(|c| c.status == STATUS_OK) {
+        "ready"
+    } else {
+        "not ready"
+    };
+    Readiness { status, checks }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_draining, check_thread_pool, get_readiness, CheckResult, STATUS_FAIL};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_check_thread_pool() {
+        assert_eq!(
+            check_thread_pool(5, 0, 1),
+            CheckResult::ok(String::from("1 of 5 workers busy, 0 queued"))
+        );
+        assert_eq!(check_thread_pool(5, 5, 5).status, "ok");
+        assert_eq!(check_thread_pool(5, 6, 5).status, STATUS_FAIL);
+    }
+
+    #[test]
+    fn test_get_readiness() {
+        let mut checks = BTreeMap::new();
+        checks.insert("storage", CheckResult::ok(String::from("ping took 0.3ms")));
+        checks.insert("draining", check_draining(false));
+        let readiness = get_readiness(checks);
+        assert_eq!(readiness.status, "ready");
+
+        let mut checks = BTreeMap::new();
+        checks.insert("storage", CheckResult::ok(String::from("ping took 0.3ms")));
+        checks.insert("draining", check_draining(true));
+        let readiness = get_readiness(checks);
+        assert_eq!(readiness.status, "not ready");
+        assert_eq!(
+            serde_json::to_string(&readiness).unwrap(),
+            r#"{"status":"not ready","checks":{"draining":{"status":"fail","message":"draining for shutdown"},"storage":{"status":"ok","message":"ping took 0.3ms"}}}"#
+        );
+    }
+}
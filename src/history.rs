@@ -0,0 +1,163 @@
+use chrono::Utc;
+use redis::{Commands, Connection, RedisError};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+extern crate redis;
+use crate::{telemetry, url_record::URLRecord};
+
+// how many versions of a link are kept, the oldest ones are dropped first
+pub const MAX_HISTORY_ENTRIES: isize = 100;
+// longest actor name taken from the X-Actor header
+const MAX_ACTOR_LEN: usize = 64;
+
+pub fn history_key(url_id: &str) -> String {
+    format!("short_url_history::{}", url_id)
+}
+
+// HistoryEntry is a version of a link, as it was right after the change
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HistoryEntry {
+    pub version: u32,
+    pub timestamp: i64,
+    pub actor: String,
+    // create, update, add_alias, remove_alias, rollback, delete or restore
+    pub action: String,
+    // version a rollback went back to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub restored_version: Option<u32>,
+    pub record: URLRecord,
+}
+
+impl HistoryEntry {
+    pub fn new(url_record: &URLRecord, actor: &str, action: &str) -> HistoryEntry {
+        HistoryEntry {
+            version: url_record.version,
+            timestamp: Utc::now().timestamp(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            restored_version: None,
+            record: url_record.clone(),
+        }
+    }
+
+    // to_public_value is the entry as shown to api clients, without the password hash
+    pub fn to_public_value(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        value["record"] = self.record.to_public_value();
+        value
+    }
+}
+
+// record_change appends the new version of the link to its history
+pub fn record_change(redis_conn: &mut Connection, entry: &HistoryEntry) -> Result<(), RedisError> {
+    let key = history_key(&entry.record.id);
+    let _: i64 = telemetry::traced("redis RPUSH", || {
+        redis_conn.rpush(&key, serde_json::to_string(entry).unwrap())
+    })?;
+    telemetry::traced("redis LTRIM", || {
+        redis_conn.ltrim(&key, -MAX_HISTORY_ENTRIES, -1)
+    })
+}
+
+// record_baseline keeps the link as it was before its history was kept, as its version 1, so
+// it can be rolled back to as well; links created since then already have it
+pub fn record_baseline(
+    redis_conn: &mut Connection,
+    url_record: &mut URLRecord,
+) -> Result<(), RedisError> {
+    if url_record.version > 0 {
+        return Ok(());
+    }
+    url_record.version = 1;
+    // already kept by an earlier change which didn't go through
+    let kept: bool = telemetry::traced("redis EXISTS", || {
+        redis_conn.exists(history_key(&url_record.id))
+    })?;
+    if kept {
+        return Ok(());
+    }
+    let entry = HistoryEntry {
+        timestamp: url_record.timestamp,
+        ..HistoryEntry::new(url_record, "unknown", "create")
+    };
+    record_change(redis_conn, &entry)
+}
+
+// get_history returns the kept versions of the link, oldest first
+pub fn get_history(
+    redis_conn: &mut Connection,
+    url_id: &str,
+) -> Result<Vec<HistoryEntry>, RedisError> {
+    let entries: Vec<String> = telemetry::traced("redis LRANGE", || {
+        redis_conn.lrange(history_key(url_id), 0, -1)
+    })?;
+    // nothing useful can be done with broken entries
+    Ok(entries
+        .iter()
+        .filter_map(|e| serde_json::from_str(e).ok())
+        .collect())
+}
+
+// wipe removes the history of the link
+pub fn wipe(redis_conn: &mut Connection, url_id: &str) -> Result<i32, RedisError> {
+    telemetry::traced("redis DEL", || redis_conn.del(history_key(url_id)))
+}
+
+// get_actor names who made a change: the X-Actor header when the client sends one, otherwise
+// the session, by a short hash of its token so the token itself isn't stored
+pub fn get_actor(actor_header: &str, session_token: &str) -> String {
+    let actor: String = actor_header
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_ACTOR_LEN)
+        .collect();
+    if !actor.is_empty() {
+        return actor;
+    }
+    if session_token.is_empty() {
+        return "anonymous".to_string();
+    }
+    let digest = Sha256::digest(session_token.as_bytes());
+    let hash: String = digest[..4].iter().map(|b| format!("{:02x}", b)).collect();
+    format!("session:{}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_actor, HistoryEntry};
+    use crate::url_record::URLRecord;
+
+    #[test]
+    fn test_get_actor() {
+        assert_eq!(get_actor("  alice ", "token"), "alice");
+        assert_eq!(get_actor("", ""), "anonymous");
+        assert_eq!(get_actor(&"x".repeat(100), ""), "x".repeat(64));
+
+        let actor = get_actor("", "secret-token");
+        assert!(actor.starts_with("session:"));
+        assert_eq!(actor.len(), "session:".len() + 8);
+        assert!(!actor.contains("secret"));
+        assert_eq!(actor, get_actor(" ", "secret-token"));
+        assert_ne!(actor, get_actor("", "other-token"));
+    }
+
+    #[test]
+    fn test_history_entry_to_public_value() {
+        let mut url_record = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"https://2beens.xyz","timestamp":1,"hits":0,"password_hash":"$argon2id$x"}"#.to_string(),
+        );
+        url_record.version = 2;
+        let entry = HistoryEntry::new(&url_record, "alice", "update");
+        let value = entry.to_public_value();
+        assert_eq!(value["version"], 2);
+        assert_eq!(value["actor"], "alice");
+        assert_eq!(value["action"], "update");
+        assert!(value.get("restored_version").is_none());
+        assert_eq!(value["record"]["password_protected"], true);
+        assert!(value["record"].get("password_hash").is_none());
+    }
+}
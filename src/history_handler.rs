@@ -0,0 +1,329 @@
+use chrono::Utc;
+use http::StatusCode;
+use log::debug;
+use redis::{Commands, Connection, RedisError};
+use serde_json::Value;
+use std::net::TcpStream;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+extern crate redis;
+use crate::{
+    alias_handler::respond_not_found,
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    metrics::Metrics,
+    new_handler::is_blocked_url,
+    stats_handler::get_id_param,
+    telemetry,
+    url_record::URLRecord,
+};
+
+pub struct HistoryHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+    // links can't be rolled back to these domains, and their subdomains
+    blocked_domains: Vec<String>,
+}
+
+impl HistoryHandler {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<HistoryHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(HistoryHandler {
+            redis_conn,
+            metrics,
+            blocked_domains: vec![],
+        })
+    }
+
+    pub fn set_blocked_domains(&mut self, blocked_domains: &[String]) {
+        self.blocked_domains = blocked_domains
+            .iter()
+            .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+    }
+
+    // handle_history lists the kept versions of a link, newest first, expects the path in form
+    // of: /history?id=<url id>
+    pub fn handle_history(&mut self, stream: TcpStream, path: &str) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let url_key = format!("short_url::{}", id);
+        match telemetry::traced("redis EXISTS", || {
+            self.redis_conn.exists::<&String, bool>(&url_key)
+        }) {
+            Ok(true) => {}
+            Ok(false) => {
+                respond_not_found(&mut self.redis_conn, &self.metrics, stream, id);
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute EXISTS for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        }
+
+        let entries = match history::get_history(&mut self.redis_conn, id) {
+            Ok(entries) => entries,
+            Err(err) => {
+                debug!("failed to get history of [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        let entries: Vec<Value> = entries
+            .iter()
+            .rev()
+            .map(HistoryEntry::to_public_value)
+            .collect();
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            Value::Array(entries).to_string(),
+        );
+    }
+
+    // handle_rollback sets a link back to one of its earlier versions, expects the path in form
+    // of: /rollback?id=<url id>, and the version in the post body; this is recorded as a new
+    // version, so a rollback can be rolled back as well
+    pub fn handle_rollback(
+        &mut self,
+        stream: TcpStream,
+        path: &str,
+        post_body: String,
+        content_type: String,
+        actor: &str,
+    ) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let version = match get_version_from_post_body(&post_body, &content_type) {
+            Ok(version) => version,
+            Err(err) => {
+                debug!("rollback: {}", err);
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+
+        let url_key = format!("short_url::{}", id);
+        let mut url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                respond_not_found(&mut self.redis_conn, &self.metrics, stream, id);
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        if url_record.deleted_at.is_some() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::CONFLICT.as_u16(),
+                format!("url [{}] is in the trash, restore it first", id),
+            );
+            return;
+        }
+
+        let snapshot = match history::get_history(&mut self.redis_conn, id) {
+            Ok(entries) => entries.into_iter().find(|e| e.version == version),
+            Err(err) => {
+                debug!("failed to get history of [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        let snapshot = match snapshot {
+            Some(entry) => entry.record,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("version {} of url [{}] not found", version, id),
+                );
+                return;
+            }
+        };
+        // the version was fine when it was made, but it may not be anymore
+        if snapshot.is_expired(Utc::now().timestamp()) {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                format!("version {} has expired", version),
+            );
+            return;
+        }
+        if snapshot
+            .urls()
+            .any(|url| is_blocked_url(url, &self.blocked_domains))
+        {
+            debug!("version {} of [{}] points to a blocked domain", version, id);
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                "domain not allowed".to_string(),
+            );
+            return;
+        }
+
+        url_record.restore(snapshot);
+        url_record.version += 1;
+        let url_record_json = url_record.to_json();
+        debug!(
+            "++ storing rolled back url record: {}",
+            url_record.to_public_value()
+        );
+        let _: () = match telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, &url_record_json)
+        }) {
+            Ok(val) => val,
+            Err(err) => {
+                debug!(
+                    "failed to execute SET for rolled back url key [{}]: {}",
+                    url_key, err
+                );
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let entry = HistoryEntry {
+            restored_version: Some(version),
+            ..HistoryEntry::new(&url_record, actor, "rollback")
+        };
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the rollback of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            url_record.to_public_value().to_string(),
+        );
+    }
+}
+
+// get_version_from_post_body returns the version to roll back to from a json body, e.g.
+// {"version":3}, or a form one, e.g. version=3
+fn get_version_from_post_body(post_body: &str, content_type: &str) -> Result<u32, String> {
+    let version = match content_type {
+        "application/json" => {
+            let parsed_json: Value =
+                serde_json::from_str(post_body).map_err(|_| "Failed to parse JSON".to_string())?;
+            match parsed_json.get("version") {
+                Some(version) => Some(
+                    version
+                        .as_u64()
+                        .and_then(|v| u32::try_from(v).ok())
+                        .ok_or("version field is not a version number".to_string())?,
+                ),
+                None => None,
+            }
+        }
+        "application/x-www-form-urlencoded" => {
+            match form_urlencoded::parse(post_body.as_bytes()).find(|(name, _)| name == "version") {
+                Some((_, version)) => Some(
+                    version
+                        .parse::<u32>()
+                        .map_err(|_| "version param is not a version number".to_string())?,
+                ),
+                None => None,
+            }
+        }
+        _ => return Err("Invalid content_type".to_string()),
+    };
+    match version {
+        Some(version) if version > 0 => Ok(version),
+        Some(_) => Err("version must be at least 1".to_string()),
+        None => Err("version param not found".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_version_from_post_body;
+
+    #[test]
+    fn test_get_version_from_post_body() {
+        let json = "application/json";
+        let form = "application/x-www-form-urlencoded";
+        assert_eq!(get_version_from_post_body(r#"{"version":3}"#, json), Ok(3));
+        assert_eq!(get_version_from_post_body("version=12", form), Ok(12));
+
+        for (body, content_type) in [
+            (r#"{"version":0}"#, json),
+            (r#"{"version":-1}"#, json),
+            (r#"{"version":"3"}"#, json),
+            (r#"{"id":"abc"}"#, json),
+            ("version=x", form),
+            ("version=", form),
+            ("id=abc", form),
+            ("version=3", "text/plain"),
+        ] {
+            assert!(
+                get_version_from_post_body(body, content_type).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+}
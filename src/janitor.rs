@@ -0,0 +1,61 @@
+use chrono::{Duration, Utc};
+use log::{debug, error, info};
+use redis::{Connection, RedisError};
+use std::thread;
+
+extern crate redis;
+use crate::{analytics, trash};
+
+// Janitor is a background job which periodically cleans up data we should not keep
+// around anymore
+pub struct Janitor {
+    redis_conn: Connection,
+    clicks_retention: Duration,
+    trash_retention: Duration,
+    interval: std::time::Duration,
+}
+
+impl Janitor {
+    pub fn new(
+        redis_conn_string: &String,
+        clicks_retention: Duration,
+        trash_retention: Duration,
+        interval: std::time::Duration,
+    ) -> Result<Janitor, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(Janitor {
+            redis_conn,
+            clicks_retention,
+            trash_retention,
+            interval,
+        })
+    }
+
+    pub fn start(mut self) -> thread::JoinHandle<()> {
+        info!(
+            "starting janitor, clicks retention: {} days, trash retention: {} days, runs every {:?}",
+            self.clicks_retention.num_days(),
+            self.trash_retention.num_days(),
+            self.interval
+        );
+        thread::spawn(move || loop {
+            self.run_once();
+            thread::sleep(self.interval);
+        })
+    }
+
+    pub fn run_once(&mut self) {
+        let cutoff = (Utc::now() - self.clicks_retention).timestamp();
+        match analytics::purge_expired_clicks(&mut self.redis_conn, cutoff) {
+            Ok(purged) => debug!("janitor purged {} expired clicks", purged),
+            Err(err) => error!("janitor failed to purge expired clicks: {}", err),
+        }
+
+        let cutoff = (Utc::now() - self.trash_retention).timestamp();
+        match trash::purge_expired(&mut self.redis_conn, cutoff) {
+            Ok(purged) => debug!("janitor purged {} links from the trash", purged),
+            Err(err) => error!("janitor failed to purge the trash: {}", err),
+        }
+    }
+}
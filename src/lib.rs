@@ -1,10 +1,33 @@
+pub mod access_log;
+pub mod alias_handler;
+pub mod analytics;
 pub mod auth_service;
+pub mod client_ip;
+pub mod config;
 pub mod delete_handler;
+pub mod geoip;
 pub mod get_all_handler;
 pub mod handlers;
+pub mod health_handler;
+pub mod history;
+pub mod history_handler;
+pub mod janitor;
 pub mod link_handler;
+pub mod link_password;
+pub mod metrics;
 pub mod new_handler;
+pub mod preview;
+pub mod privacy;
+pub mod qr_handler;
+pub mod rate_limit;
+pub mod redact;
 pub mod router;
 pub mod server;
+pub mod stats_handler;
+pub mod targeting;
+pub mod telemetry;
 pub mod thread_pool;
+pub mod trash;
+pub mod trash_handler;
+pub mod update_handler;
 pub mod url_record;
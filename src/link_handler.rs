@@ -1,28 +1,188 @@
-use crate::{handlers::Handlers, url_record::URLRecord};
+use crate::{
+    alias_handler::get_url_record,
+    analytics::Analytics,
+    geoip::GeoIp,
+    handlers::Handlers,
+    link_password::{password_form, sign_unlock, verify_password, verify_unlock},
+    metrics::Metrics,
+    preview::{render_preview, DEFAULT_TEMPLATE},
+    privacy::IpAnonymization,
+    rate_limit::RateLimiter,
+    targeting::choose_target,
+    telemetry,
+    url_record::{URLRecord, Variant},
+};
+use chrono::{DateTime, Duration, Utc};
 use http::StatusCode;
 use log::debug;
-use std::net::TcpStream;
+use rand::{thread_rng, Rng};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, TcpStream};
+use std::sync::Arc;
+use std::time::Instant;
+use url::{form_urlencoded, Url};
 
 extern crate redis;
 use redis::{Commands, Connection, RedisError};
 
+const VARIANT_COOKIE: &str = "rus_variant";
+// 30 days
+const VARIANT_COOKIE_MAX_AGE: u32 = 30 * 24 * 60 * 60;
+
+// how long a visitor who entered the password of a link isn't asked for it again
+const UNLOCK_COOKIE: &str = "rus_unlock";
+const UNLOCK_COOKIE_MAX_AGE: i64 = 60 * 60;
+// wrong passwords allowed per link and client ip within the window, before that client is locked
+// out of the link for the rest of it
+const MAX_FAILED_UNLOCKS: u32 = 5;
+const FAILED_UNLOCKS_WINDOW: std::time::Duration = std::time::Duration::from_secs(15 * 60);
+
+// LinkRequest holds what's needed from a request to a link, besides its path
+pub struct LinkRequest<'a> {
+    pub client_ip: Option<IpAddr>,
+    // false for requests which only look at the link, e.g. HEAD ones
+    pub record_hit: bool,
+    pub cookie: &'a str,
+    pub user_agent: &'a str,
+    pub accept_language: &'a str,
+    // the form with the password of a protected link, when posted
+    pub post_body: Option<&'a str>,
+    // the posted password, when it was verified before the request got to the handler
+    pub verified_password: Option<&'a VerifiedPassword>,
+}
+
+// PasswordCheck is a password posted to a protected link, to be verified before the request
+// is routed, as argon2 is too slow to run while the router is locked
+pub struct PasswordCheck {
+    password: String,
+    password_hash: String,
+}
+
+impl PasswordCheck {
+    pub fn verify(self) -> VerifiedPassword {
+        let ok = verify_password(&self.password, &self.password_hash);
+        VerifiedPassword {
+            password: self.password,
+            password_hash: self.password_hash,
+            ok,
+        }
+    }
+}
+
+pub struct VerifiedPassword {
+    password: String,
+    password_hash: String,
+    ok: bool,
+}
+
 pub struct LinkHandler {
     redis_conn: Connection,
+    analytics: Analytics,
+    metrics: Arc<Metrics>,
+    redirect_status: StatusCode,
+    // Cache-Control of redirects, e.g. "private, max-age=90" or "no-store"
+    cache_control: String,
+    // whether visitors keep getting the same variant of a split link, via a cookie
+    sticky_variants: bool,
+    // html shown for links which haven't started yet, {starts_at} is replaced with the time
+    not_started_page: Option<String>,
+    // html template of the preview pages, see preview::render_preview
+    preview_template: String,
+    // signs the cookies of visitors who entered the password of a link
+    cookie_secret: Vec<u8>,
+    // wrong passwords by link id and client ip, so a client can't lock others out of a link
+    failed_unlocks: RateLimiter<(String, Option<IpAddr>)>,
 }
 
 impl LinkHandler {
-    pub fn new(redis_conn_string: &String) -> Result<LinkHandler, RedisError> {
+    pub fn new(
+        redis_conn_string: &String,
+        geoip: Option<GeoIp>,
+        ip_anonymization: IpAnonymization,
+        metrics: Arc<Metrics>,
+    ) -> Result<LinkHandler, RedisError> {
         let redis_client = redis::Client::open(String::from(redis_conn_string))?;
         let redis_conn = redis_client.get_connection()?;
-        Ok(LinkHandler { redis_conn })
+        let analytics = Analytics::new(
+            redis_conn_string,
+            geoip,
+            ip_anonymization,
+            Arc::clone(&metrics),
+        )?;
+        Ok(LinkHandler {
+            redis_conn,
+            analytics,
+            metrics,
+            redirect_status: StatusCode::MOVED_PERMANENTLY,
+            cache_control: String::new(),
+            sticky_variants: false,
+            not_started_page: None,
+            preview_template: DEFAULT_TEMPLATE.to_string(),
+            cookie_secret: thread_rng().gen::<[u8; 32]>().to_vec(),
+            failed_unlocks: RateLimiter::new(MAX_FAILED_UNLOCKS, FAILED_UNLOCKS_WINDOW),
+        })
+    }
+
+    // set_redirect_status sets the default redirect status of links, e.g. 302
+    pub fn set_redirect_status(&mut self, status: u16) {
+        match StatusCode::from_u16(status) {
+            Ok(status) if status.is_redirection() => self.redirect_status = status,
+            _ => debug!("ignoring invalid redirect status: {}", status),
+        }
+    }
+
+    pub fn set_cache_control(&mut self, cache_control: &str) {
+        self.cache_control = cache_control.to_string();
     }
 
-    pub fn handle_link(&mut self, stream: TcpStream, path: &str) {
-        let url_id;
-        match path.strip_prefix("/l/") {
-            Some(url_id_from_path) => {
-                url_id = String::from(url_id_from_path);
+    pub fn set_sticky_variants(&mut self, sticky_variants: bool) {
+        self.sticky_variants = sticky_variants;
+    }
+
+    // set_cookie_secret sets the key signing the cookies of password protected links, the
+    // random one is kept when it's empty
+    pub fn set_cookie_secret(&mut self, cookie_secret: &str) {
+        if !cookie_secret.is_empty() {
+            self.cookie_secret = cookie_secret.as_bytes().to_vec();
+        }
+    }
+
+    // set_preview_template sets the template of the preview pages, the default one is used when
+    // not given
+    pub fn set_preview_template(&mut self, preview_template: Option<String>) {
+        self.preview_template = preview_template.unwrap_or_else(|| DEFAULT_TEMPLATE.to_string());
+    }
+
+    pub fn set_not_started_page(&mut self, not_started_page: Option<String>) {
+        self.not_started_page = not_started_page;
+    }
+
+    // choose_variant picks the variant of a split link to redirect to, the one from the cookie
+    // if variants are sticky and its url is still among the variants
+    fn choose_variant(&self, url_record: &URLRecord, cookie_header: &str) -> Option<usize> {
+        if url_record.variants.is_empty() {
+            return None;
+        }
+        if self.sticky_variants {
+            let from_cookie = get_cookie(cookie_header, VARIANT_COOKIE)
+                .and_then(|token| find_variant(&url_record.variants, token));
+            if from_cookie.is_some() {
+                return from_cookie;
             }
+        }
+        let total_weight: u32 = url_record.variants.iter().map(|v| v.weight).sum();
+        Some(pick_variant(
+            &url_record.variants,
+            thread_rng().gen_range(0..total_weight.max(1)),
+        ))
+    }
+
+    // handle_link redirects to the link, recording the hit unless the request only looks at it;
+    // the destination can depend on the client platform and language, and the variant it got
+    // before
+    pub fn handle_link(&mut self, stream: TcpStream, path: &str, request: &LinkRequest) {
+        let link_path = match path.strip_prefix("/l/") {
+            Some(link_path) => link_path,
             None => {
                 Handlers::respond_with_status_code(
                     stream,
@@ -31,47 +191,675 @@ impl LinkHandler {
                 );
                 return;
             }
-        }
+        };
+
+        log_mdc::insert("link_id", link_path);
 
-        debug!(">>> will redirect to url id: [{}]", url_id);
+        match self.find_link(link_path) {
+            Ok(Some((url_id, path_suffix, preview_requested, mut url_record))) => {
+                log_mdc::insert("link_id", &url_id);
+                debug!(">>> will redirect to url id: [{}]", url_id);
+                // cookies and the password form stay with the id the link was reached through
+                let alias = Some(url_id.as_str()).filter(|id| *id != url_record.id);
+                let now = Utc::now();
+                if url_record.deleted_at.is_some() {
+                    debug!(">>> url [{}] is in the trash", url_record.id);
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::GONE.as_u16(),
+                        format!("url [{}] has been deleted", url_id),
+                    );
+                    return;
+                }
+                if url_record.is_expired(now.timestamp()) {
+                    debug!(">>> url [{}] has expired", url_record.id);
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::GONE.as_u16(),
+                        format!("url [{}] has expired", url_record.id),
+                    );
+                    return;
+                }
+                if !url_record.has_started(now.timestamp()) {
+                    debug!(">>> url [{}] hasn't started yet", url_record.id);
+                    let starts_at = DateTime::from_timestamp(url_record.starts_at.unwrap_or(0), 0)
+                        .unwrap_or(now)
+                        .format("%Y-%m-%d %H:%M UTC")
+                        .to_string();
+                    let message = match &self.not_started_page {
+                        Some(page) => page.replace("{starts_at}", &starts_at),
+                        None => format!(
+                            "url [{}] is not available until {}",
+                            url_record.id, starts_at
+                        ),
+                    };
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::NOT_FOUND.as_u16(),
+                        message,
+                    );
+                    return;
+                }
 
-        let url_key = format!("short_url::{}", url_id);
-        match self.redis_conn.get::<String, String>(url_key) {
-            Ok(url_record) => {
-                let mut url_record = URLRecord::from_json(url_id, &url_record);
-                debug!(">>> found url to redirect to: [{}]", url_record.url);
-                Handlers::handle_redirect(stream, url_record.url.to_string());
+                // protected links ask for the password, unless the visitor entered it lately
+                let mut unlock_cookie = None;
+                if let Some(password_hash) = &url_record.password_hash {
+                    let unlocked =
+                        self.is_unlocked(&url_record.id, password_hash, request.cookie, now);
+                    if !unlocked {
+                        let password = match request.post_body {
+                            Some(post_body) => get_form_param(post_body, "password"),
+                            None => {
+                                Handlers::respond_with_status_code(
+                                    stream,
+                                    StatusCode::OK.as_u16(),
+                                    password_form(&url_id, None),
+                                );
+                                return;
+                            }
+                        };
+                        let unlock_key = (url_record.id.to_string(), request.client_ip);
+                        if let Some(retry_after) =
+                            self.failed_unlocks.retry_after(&unlock_key, Instant::now())
+                        {
+                            debug!(">>> too many wrong passwords for [{}]", url_record.id);
+                            Handlers::handle_too_many_requests(stream, retry_after.as_secs() + 1);
+                            return;
+                        }
+                        let password = password.unwrap_or_default();
+                        let password_ok = match request.verified_password {
+                            Some(v)
+                                if v.password == password && v.password_hash == *password_hash =>
+                            {
+                                v.ok
+                            }
+                            // e.g. the password of the link was changed in the meantime
+                            _ => verify_password(&password, password_hash),
+                        };
+                        if !password_ok {
+                            debug!(">>> wrong password for [{}]", url_record.id);
+                            let _ = self.failed_unlocks.check(unlock_key, Instant::now());
+                            Handlers::respond_with_status_code(
+                                stream,
+                                StatusCode::UNAUTHORIZED.as_u16(),
+                                password_form(&url_id, Some("Wrong password.")),
+                            );
+                            return;
+                        }
+                        unlock_cookie = Some(unlock_cookie_header(
+                            &url_id,
+                            &sign_unlock(
+                                &self.cookie_secret,
+                                &url_record.id,
+                                password_hash,
+                                now.timestamp() + UNLOCK_COOKIE_MAX_AGE,
+                            ),
+                        ));
+                    }
+                } else if request.post_body.is_some() {
+                    Handlers::handle_method_not_allowed(stream, "POST");
+                    return;
+                }
 
+                // targeted links go to the destination for the client, if there's one, and
+                // split links to one of their variants
+                let target = choose_target(
+                    &url_record.targets,
+                    request.user_agent,
+                    request.accept_language,
+                );
+                let variant = match target {
+                    Some(_) => None,
+                    None => self.choose_variant(&url_record, request.cookie),
+                };
+                let destination = match (target, variant) {
+                    (Some(target), _) => target.url.to_string(),
+                    (None, Some(i)) => url_record.variants[i].url.to_string(),
+                    (None, None) => url_record.current_url(now.timestamp()).to_string(),
+                };
+
+                // with path forwarding, what follows the id is appended to the target url
+                let target_url = if path_suffix.is_empty() {
+                    destination.to_string()
+                } else {
+                    match forward_path(&destination, path_suffix) {
+                        Ok(target_url) => target_url,
+                        Err(err) => {
+                            debug!(">>> can't forward [{}]: {}", path_suffix, err);
+                            Handlers::respond_with_status_code(
+                                stream,
+                                StatusCode::BAD_REQUEST.as_u16(),
+                                err,
+                            );
+                            return;
+                        }
+                    }
+                };
+
+                let mut headers = vec![];
+                if !url_record.targets.is_empty() {
+                    headers.push(("Vary", "User-Agent, Accept-Language".to_string()));
+                }
+                if let (Some(variant), true) = (variant, self.sticky_variants) {
+                    headers.push((
+                        "Set-Cookie",
+                        variant_cookie(&url_id, &url_record.variants[variant].url),
+                    ));
+                }
+                if let Some(unlock_cookie) = unlock_cookie {
+                    headers.push(("Set-Cookie", unlock_cookie));
+                }
+
+                if preview_requested || url_record.preview {
+                    debug!(">>> showing preview of url: [{}]", target_url);
+                    headers.push(("Cache-Control", "no-store".to_string()));
+                    let page = render_preview(
+                        &self.preview_template,
+                        &url_id,
+                        &target_url,
+                        url_record.timestamp,
+                        url_record.hits,
+                    );
+                    Handlers::html_response(stream, StatusCode::OK.as_u16(), &headers, page);
+                } else {
+                    debug!(">>> found url to redirect to: [{}]", target_url);
+                    // once the password is posted, the browser has to follow with a GET, so the
+                    // password isn't posted on to the url
+                    let redirect_status = match request.post_body {
+                        Some(_) => StatusCode::SEE_OTHER,
+                        None => url_record
+                            .redirect_type
+                            .and_then(|t| StatusCode::from_u16(t).ok())
+                            .filter(StatusCode::is_redirection)
+                            .unwrap_or(self.redirect_status),
+                    };
+                    // redirects of protected links can't be cached, or they'd skip the password
+                    let cache_control = match url_record.password_hash {
+                        Some(_) => "no-store",
+                        None => &self.cache_control,
+                    };
+                    headers.extend(get_cache_headers(
+                        cache_control,
+                        url_record.next_change(now.timestamp()),
+                        now,
+                    ));
+                    Handlers::handle_redirect(stream, redirect_status, target_url, &headers);
+                }
+
+                // the preview of a link which is always previewed counts as its hit, asking for
+                // one with a trailing + only checks where the link goes
+                if !request.record_hit || preview_requested {
+                    return;
+                }
                 // increase hits count for this link
                 self.link_hits_inc(&mut url_record);
+                self.analytics.record_click(
+                    &url_record.id,
+                    request.client_ip,
+                    variant.map(|_| destination.as_str()),
+                    alias,
+                );
+            }
+            Ok(None) => {
+                debug!(">>> url [{}] not found", link_path);
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("url [{}] not found", link_path),
+                );
             }
             Err(e) => {
+                debug!("failed to get url record [{}]: {}", link_path, e);
+                self.metrics.inc_redis_errors();
                 Handlers::respond_with_status_code(
                     stream,
-                    StatusCode::BAD_REQUEST.as_u16(),
-                    String::from(format!("redis err: {}", e)),
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    format!("redis err: {}", e),
                 );
             }
         }
     }
 
+    // password_check returns the password posted to a protected link, for it to be verified
+    // before the request is handled; None when there's nothing to verify, or the client has
+    // to wait after too many wrong passwords anyway
+    pub fn password_check(&mut self, path: &str, request: &LinkRequest) -> Option<PasswordCheck> {
+        let post_body = request.post_body?;
+        let link_path = path.strip_prefix("/l/")?;
+        // lookup errors are left for the request itself to report
+        let (_, _, _, url_record) = self.find_link(link_path).ok()??;
+        let password_hash = url_record.password_hash?;
+        if self.is_unlocked(&url_record.id, &password_hash, request.cookie, Utc::now()) {
+            return None;
+        }
+        let unlock_key = (url_record.id, request.client_ip);
+        if self
+            .failed_unlocks
+            .retry_after(&unlock_key, Instant::now())
+            .is_some()
+        {
+            return None;
+        }
+        Some(PasswordCheck {
+            password: get_form_param(post_body, "password").unwrap_or_default(),
+            password_hash,
+        })
+    }
+
+    // is_unlocked tells whether the cookie shows the visitor entered the password lately
+    fn is_unlocked(
+        &self,
+        link_id: &str,
+        password_hash: &str,
+        cookie_header: &str,
+        now: DateTime<Utc>,
+    ) -> bool {
+        get_cookie(cookie_header, UNLOCK_COOKIE).is_some_and(|v| {
+            verify_unlock(
+                &self.cookie_secret,
+                link_id,
+                password_hash,
+                v,
+                now.timestamp(),
+            )
+        })
+    }
+
+    // find_link looks up the link the path following /l/ points to, along with the id it was
+    // reached through, the path suffix to forward and whether the preview was asked for
+    fn find_link<'a>(
+        &mut self,
+        link_path: &'a str,
+    ) -> Result<Option<(String, &'a str, bool, URLRecord)>, RedisError> {
+        for (url_id, path_suffix, preview_requested) in link_candidates(link_path) {
+            let url_record = match get_url_record(&mut self.redis_conn, url_id)? {
+                Some(url_record) => url_record,
+                None => continue,
+            };
+            // only links with path forwarding take a suffix, ids may contain / and ? too
+            if !path_suffix.is_empty() && !url_record.forward_path {
+                debug!(">>> url [{}] doesn't forward paths", url_record.id);
+                continue;
+            }
+            return Ok(Some((
+                url_id.to_string(),
+                path_suffix,
+                preview_requested,
+                url_record,
+            )));
+        }
+        Ok(None)
+    }
+
     pub fn link_hits_inc(&mut self, url_record: &mut URLRecord) {
         url_record.hits += 1;
-        println!(
+        debug!(
             "++ updating link {} hits, new val: {}",
             url_record.id, url_record.hits
         );
 
         let url_key = format!("short_url::{}", url_record.id);
         let url_record_json = url_record.to_json();
-        let _: () = match self.redis_conn.set(&url_key, String::from(url_record_json)) {
+        let _: () = match telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, url_record_json)
+        }) {
             Ok(val) => val,
             Err(err) => {
                 debug!(
                     "failed to execute SET for updated url with key [{}]: {}",
                     url_key, err
                 );
+                self.metrics.inc_redis_errors();
             }
         };
     }
 }
+
+// pick_variant maps the roll (below the total weight) to a variant, by the weights
+fn pick_variant(variants: &[Variant], roll: u32) -> usize {
+    let mut upper_bound = 0;
+    for (i, variant) in variants.iter().enumerate() {
+        upper_bound += variant.weight;
+        if roll < upper_bound {
+            return i;
+        }
+    }
+    variants.len().saturating_sub(1)
+}
+
+// variant_token identifies a variant by its url rather than its position, so a cookie set
+// before the variants were edited does not point at a different destination
+fn variant_token(variant_url: &str) -> String {
+    let digest = Sha256::digest(variant_url.as_bytes());
+    digest[..8].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// find_variant returns the variant the token was made from, None if it is gone
+fn find_variant(variants: &[Variant], token: &str) -> Option<usize> {
+    variants.iter().position(|v| variant_token(&v.url) == token)
+}
+
+// variant_cookie remembers the variant served for the link, scoped to the link's path
+fn variant_cookie(url_id: &str, variant_url: &str) -> String {
+    format!(
+        "{}={}; Path=/l/{}; Max-Age={}; HttpOnly; SameSite=Lax",
+        VARIANT_COOKIE,
+        variant_token(variant_url),
+        url_id,
+        VARIANT_COOKIE_MAX_AGE
+    )
+}
+
+// unlock_cookie_header lets the visitor through the password of the link for a while
+fn unlock_cookie_header(url_id: &str, value: &str) -> String {
+    format!(
+        "{}={}; Path=/l/{}; Max-Age={}; HttpOnly; SameSite=Lax",
+        UNLOCK_COOKIE, value, url_id, UNLOCK_COOKIE_MAX_AGE
+    )
+}
+
+fn get_form_param(post_body: &str, name: &str) -> Option<String> {
+    form_urlencoded::parse(post_body.as_bytes())
+        .find(|(n, _)| n == name)
+        .map(|(_, value)| value.into_owned())
+}
+
+fn get_cookie<'a>(cookie_header: &'a str, name: &str) -> Option<&'a str> {
+    cookie_header
+        .split(';')
+        .filter_map(|c| c.trim().split_once('='))
+        .find(|(n, _)| *n == name)
+        .map(|(_, v)| v)
+}
+
+// link_candidates lists the ways the path following /l/ can be read, in the order they are
+// tried: as an id on its own, as an id followed by a + asking for the preview (e.g. /l/abc+),
+// and as the id of a link forwarding the rest of the path (e.g. /l/docs/intro or /l/docs+/intro)
+fn link_candidates(link_path: &str) -> Vec<(&str, &str, bool)> {
+    let mut candidates = vec![(link_path, "", false)];
+    if let Some(url_id) = link_path.strip_suffix('+') {
+        candidates.push((url_id, "", true));
+    }
+    let (url_id, path_suffix) = split_link_path(link_path);
+    if !path_suffix.is_empty() {
+        match url_id.strip_suffix('+') {
+            Some(url_id) => candidates.push((url_id, path_suffix, true)),
+            None => candidates.push((url_id, path_suffix, false)),
+        }
+    }
+    candidates.retain(|(url_id, _, _)| !url_id.is_empty());
+    candidates
+}
+
+// split_link_path splits what follows /l/ into the link id, which is the first path segment,
+// and the rest (path and query)
+fn split_link_path(link_path: &str) -> (&str, &str) {
+    match link_path.find(['/', '?']) {
+        Some(i) => (&link_path[..i], &link_path[i..]),
+        None => (link_path, ""),
+    }
+}
+
+// forward_path appends the path suffix and the query of the request to the target url; query
+// params of the request replace the same named ones of the target
+fn forward_path(target_url: &str, path_suffix: &str) -> Result<String, String> {
+    let mut url = Url::parse(target_url).map_err(|e| e.to_string())?;
+    let (suffix_path, suffix_query) = match path_suffix.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (path_suffix, ""),
+    };
+
+    // the forwarded path stays under the path of the target
+    if suffix_path.split('/').any(|s| s == ".." || s == ".") {
+        return Err(String::from("invalid path"));
+    }
+    if !suffix_path.trim_matches('/').is_empty() {
+        let path = format!(
+            "{}/{}",
+            url.path().trim_end_matches('/'),
+            suffix_path.trim_start_matches('/')
+        );
+        url.set_path(&path);
+    }
+
+    if !suffix_query.is_empty() {
+        let request_params: Vec<(String, String)> = form_urlencoded::parse(suffix_query.as_bytes())
+            .into_owned()
+            .collect();
+        let mut params: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(name, _)| !request_params.iter().any(|(n, _)| n == name))
+            .collect();
+        params.extend(request_params);
+        url.query_pairs_mut().clear().extend_pairs(params);
+    }
+
+    Ok(url.to_string())
+}
+
+// get_cache_headers returns the caching headers of a redirect; the max-age is capped by the
+// next change of the link (its expiry or scheduled switch), so a link is never cached beyond
+// it, and set for it even when not configured, as permanent redirects are otherwise cached for
+// good
+fn get_cache_headers(
+    cache_control: &str,
+    changes_at: Option<i64>,
+    now: DateTime<Utc>,
+) -> Vec<(&'static str, String)> {
+    let mut directives: Vec<&str> = cache_control
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .collect();
+    if directives.contains(&"no-store") {
+        return vec![
+            ("Cache-Control", directives.join(", ")),
+            ("Expires", "0".to_string()),
+        ];
+    }
+
+    let max_age = directives
+        .iter()
+        .find_map(|d| d.strip_prefix("max-age="))
+        .and_then(|v| v.parse::<i64>().ok());
+    let until_change = changes_at.map(|t| (t - now.timestamp()).max(0));
+    let max_age = match (max_age, until_change) {
+        (Some(max_age), Some(until_change)) => Some(max_age.min(until_change)),
+        (max_age, until_change) => max_age.or(until_change),
+    };
+    directives.retain(|d| !d.starts_with("max-age="));
+
+    let mut cache_control = directives.join(", ");
+    let mut headers = vec![];
+    if let Some(max_age) = max_age {
+        if !cache_control.is_empty() {
+            cache_control.push_str(", ");
+        }
+        cache_control.push_str(&format!("max-age={}", max_age));
+        headers.push((
+            "Expires",
+            (now + Duration::seconds(max_age))
+                .format("%a, %d %b %Y %H:%M:%S GMT")
+                .to_string(),
+        ));
+    }
+    if !cache_control.is_empty() {
+        headers.insert(0, ("Cache-Control", cache_control));
+    }
+    headers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        find_variant, forward_path, get_cache_headers, get_cookie, link_candidates, pick_variant,
+        split_link_path, variant_cookie, variant_token,
+    };
+    use crate::url_record::Variant;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_get_cache_headers() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        let expires_at = now.timestamp() + 30;
+
+        assert_eq!(
+            get_cache_headers("private, max-age=90", None, now),
+            vec![
+                ("Cache-Control", "private, max-age=90".to_string()),
+                ("Expires", "Fri, 01 Mar 2024 12:01:30 GMT".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_cache_headers("private, max-age=90", Some(expires_at), now),
+            vec![
+                ("Cache-Control", "private, max-age=30".to_string()),
+                ("Expires", "Fri, 01 Mar 2024 12:00:30 GMT".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_cache_headers("", Some(expires_at), now),
+            vec![
+                ("Cache-Control", "max-age=30".to_string()),
+                ("Expires", "Fri, 01 Mar 2024 12:00:30 GMT".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_cache_headers("no-store", Some(expires_at), now),
+            vec![
+                ("Cache-Control", "no-store".to_string()),
+                ("Expires", "0".to_string()),
+            ]
+        );
+        assert_eq!(
+            get_cache_headers("private", None, now),
+            vec![("Cache-Control", "private".to_string())]
+        );
+        assert!(get_cache_headers("", None, now).is_empty());
+    }
+
+    #[test]
+    fn test_pick_variant() {
+        let variants = vec![
+            Variant {
+                url: "http://a.xyz".to_string(),
+                weight: 70,
+            },
+            Variant {
+                url: "http://b.xyz".to_string(),
+                weight: 30,
+            },
+        ];
+        assert_eq!(pick_variant(&variants, 0), 0);
+        assert_eq!(pick_variant(&variants, 69), 0);
+        assert_eq!(pick_variant(&variants, 70), 1);
+        assert_eq!(pick_variant(&variants, 99), 1);
+    }
+
+    #[test]
+    fn test_find_variant() {
+        let variants = vec![
+            Variant {
+                url: "http://a.xyz".to_string(),
+                weight: 50,
+            },
+            Variant {
+                url: "http://b.xyz".to_string(),
+                weight: 50,
+            },
+        ];
+        assert_eq!(
+            find_variant(&variants, &variant_token("http://b.xyz")),
+            Some(1)
+        );
+        // the variant was removed, or the cookie is from before variants were keyed by url
+        assert_eq!(
+            find_variant(&variants, &variant_token("http://c.xyz")),
+            None
+        );
+        assert_eq!(find_variant(&variants, "1"), None);
+        assert_eq!(
+            find_variant(&variants[1..], &variant_token("http://b.xyz")),
+            Some(0)
+        );
+    }
+
+    #[test]
+    fn test_variant_cookie() {
+        assert_eq!(
+            variant_cookie("abc", "https://b.example.com"),
+            format!(
+                "rus_variant={}; Path=/l/abc; Max-Age=2592000; HttpOnly; SameSite=Lax",
+                variant_token("https://b.example.com")
+            )
+        );
+        assert_eq!(
+            get_cookie("session=xyz; rus_variant=1", "rus_variant"),
+            Some("1")
+        );
+        assert_eq!(get_cookie("session=xyz", "rus_variant"), None);
+        assert_eq!(get_cookie("", "rus_variant"), None);
+    }
+
+    #[test]
+    fn test_split_link_path() {
+        assert_eq!(split_link_path("abc"), ("abc", ""));
+        assert_eq!(
+            split_link_path("docs/getting-started?x=1"),
+            ("docs", "/getting-started?x=1")
+        );
+        assert_eq!(split_link_path("abc?x=1"), ("abc", "?x=1"));
+        assert_eq!(split_link_path("abc/"), ("abc", "/"));
+    }
+
+    #[test]
+    fn test_link_candidates() {
+        assert_eq!(link_candidates("abc"), vec![("abc", "", false)]);
+        assert_eq!(
+            link_candidates("abc+"),
+            vec![("abc+", "", false), ("abc", "", true)]
+        );
+        // an existing a/b id is looked up as a whole before a forwarding link a
+        assert_eq!(
+            link_candidates("a/b"),
+            vec![("a/b", "", false), ("a", "/b", false)]
+        );
+        assert_eq!(
+            link_candidates("a/b+"),
+            vec![("a/b+", "", false), ("a/b", "", true), ("a", "/b+", false)]
+        );
+        assert_eq!(
+            link_candidates("docs+/intro?x=1"),
+            vec![("docs+/intro?x=1", "", false), ("docs", "/intro?x=1", true)]
+        );
+        assert_eq!(link_candidates("+"), vec![("+", "", false)]);
+    }
+
+    #[test]
+    fn test_forward_path() {
+        assert_eq!(
+            forward_path("https://docs.example.com", "/getting-started?x=1"),
+            Ok("https://docs.example.com/getting-started?x=1".to_string())
+        );
+        assert_eq!(
+            forward_path(
+                "https://docs.example.com/v2/?ref=short&x=0#top",
+                "/guide/install?x=1&y=2"
+            ),
+            Ok("https://docs.example.com/v2/guide/install?ref=short&x=1&y=2#top".to_string())
+        );
+        assert_eq!(
+            forward_path("https://docs.example.com/v2?ref=short", "/"),
+            Ok("https://docs.example.com/v2?ref=short".to_string())
+        );
+        assert_eq!(
+            forward_path("https://docs.example.com/v2", "?q=a%20b"),
+            Ok("https://docs.example.com/v2?q=a+b".to_string())
+        );
+        assert_eq!(
+            forward_path("https://docs.example.com/v2", "/../admin"),
+            Err("invalid path".to_string())
+        );
+    }
+}
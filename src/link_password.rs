@@ -0,0 +1,205 @@
+use crate::handlers::escape_html;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// length limits of link passwords, long ones only make hashing slower
+const MIN_PASSWORD_LEN: usize = 4;
+const MAX_PASSWORD_LEN: usize = 128;
+
+pub fn validate_password(password: &str) -> Result<(), String> {
+    let len = password.chars().count();
+    if !(MIN_PASSWORD_LEN..=MAX_PASSWORD_LEN).contains(&len) {
+        return Err(format!(
+            "password must be {} to {} characters long",
+            MIN_PASSWORD_LEN, MAX_PASSWORD_LEN
+        ));
+    }
+    Ok(())
+}
+
+// hash_password returns the argon2 hash of the password in the PHC string format, with the
+// salt and parameters included
+pub fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("failed to hash password: {}", e))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(_) => false,
+    }
+}
+
+// sign_unlock returns the value of the cookie which lets the visitor through the password of
+// the link until expires_at; it's bound to the password hash, so changing the password locks
+// the link again
+pub fn sign_unlock(secret: &[u8], url_id: &str, password_hash: &str, expires_at: i64) -> String {
+    let signature = unlock_mac(secret, url_id, password_hash, expires_at)
+        .finalize()
+        .into_bytes();
+    format!("{}.{}", expires_at, to_hex(&signature))
+}
+
+// verify_unlock checks the cookie value is an unexpired one from sign_unlock for the link
+pub fn verify_unlock(
+    secret: &[u8],
+    url_id: &str,
+    password_hash: &str,
+    cookie_value: &str,
+    now: i64,
+) -> bool {
+    let (expires_at, signature) = match cookie_value.split_once('.') {
+        Some((expires_at, signature)) => (expires_at.parse::<i64>(), from_hex(signature)),
+        None => return false,
+    };
+    match (expires_at, signature) {
+        (Ok(expires_at), Some(signature)) if expires_at > now => {
+            unlock_mac(secret, url_id, password_hash, expires_at)
+                .verify_slice(&signature)
+                .is_ok()
+        }
+        _ => false,
+    }
+}
+
+fn unlock_mac(secret: &[u8], url_id: &str, password_hash: &str, expires_at: i64) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("hmac takes keys of any size");
+    for part in [url_id, password_hash, &expires_at.to_string()] {
+        // length prefixed, so the parts can't be shifted into one another
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part.as_bytes());
+    }
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+// password_form is the page asking for the password of a link, posting it back to the link
+pub fn password_form(url_id: &str, error: Option<&str>) -> String {
+    let error = match error {
+        Some(error) => format!("<p class=\"error\">{}</p>", escape_html(error)),
+        None => String::new(),
+    };
+    format!(
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <meta name=\"robots\" content=\"noindex\"><title>Password required</title></head>\
+         <body><form method=\"post\"><p>Link <b>{}</b> is password protected.</p>{}\
+         <input type=\"password\" name=\"password\" autofocus required> \
+         <button type=\"submit\">Continue</button></form></body></html>",
+        escape_html(url_id),
+        error
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        hash_password, password_form, sign_unlock, validate_password, verify_password,
+        verify_unlock,
+    };
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("open sesame").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("open sesame", &hash));
+        assert!(!verify_password("open sesame!", &hash));
+        assert!(!verify_password("open sesame", "not a hash"));
+        // salted, so each hash differs
+        assert_ne!(hash, hash_password("open sesame").unwrap());
+    }
+
+    #[test]
+    fn test_validate_password() {
+        assert!(validate_password("abcd").is_ok());
+        assert!(validate_password("abc").is_err());
+        assert!(validate_password(&"x".repeat(129)).is_err());
+    }
+
+    #[test]
+    fn test_unlock_cookie() {
+        let secret = b"secret";
+        let cookie = sign_unlock(secret, "abc", "$argon2id$hash", 1000);
+        assert!(cookie.starts_with("1000."));
+        assert!(verify_unlock(secret, "abc", "$argon2id$hash", &cookie, 999));
+        // expired
+        assert!(!verify_unlock(
+            secret,
+            "abc",
+            "$argon2id$hash",
+            &cookie,
+            1000
+        ));
+        // another link, password, or secret
+        assert!(!verify_unlock(
+            secret,
+            "abd",
+            "$argon2id$hash",
+            &cookie,
+            999
+        ));
+        assert!(!verify_unlock(
+            secret,
+            "abc",
+            "$argon2id$other",
+            &cookie,
+            999
+        ));
+        assert!(!verify_unlock(
+            b"other",
+            "abc",
+            "$argon2id$hash",
+            &cookie,
+            999
+        ));
+        // a longer validity can't be forged
+        let forged = cookie.replacen("1000.", "9000.", 1);
+        assert!(!verify_unlock(
+            secret,
+            "abc",
+            "$argon2id$hash",
+            &forged,
+            999
+        ));
+        for invalid in ["", "1000", "1000.zz", "x.00", "1000.é0"] {
+            assert!(!verify_unlock(
+                secret,
+                "abc",
+                "$argon2id$hash",
+                invalid,
+                999
+            ));
+        }
+    }
+
+    #[test]
+    fn test_password_form() {
+        let form = password_form("<script>", Some("wrong password"));
+        assert!(form.contains("&lt;script&gt;"));
+        assert!(!form.contains("<script>"));
+        assert!(form.contains("wrong password"));
+    }
+}
@@ -0,0 +1,254 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::thread_pool::PoolStats;
+
+// upper bounds (in seconds) of the request latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    // non cumulative counts per bucket, the last one is +Inf
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        let i = LATENCY_BUCKETS
+            .iter()
+            .position(|b| value <= *b)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[i] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+// Metrics collects service metrics, rendered in the Prometheus text exposition format
+#[derive(Default)]
+pub struct Metrics {
+    // (route, status) -> count
+    requests: Mutex<BTreeMap<(String, u16), u64>>,
+    // route -> latency histogram
+    latencies: Mutex<BTreeMap<String, Histogram>>,
+    redirects: AtomicU64,
+    links_created: AtomicU64,
+    redis_errors: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn observe_request(&self, route: &str, status: u16, latency: Duration) {
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status))
+            .or_insert(0) += 1;
+        self.latencies
+            .lock()
+            .unwrap()
+            .entry(route.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn inc_redirects(&self) {
+        self.redirects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_links_created(&self) {
+        self.links_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn inc_redis_errors(&self) {
+        self.redis_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self, pool_stats: Option<&PoolStats>) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP rus_http_requests_total Handled HTTP requests by route and status.\n");
+        out.push_str("# TYPE rus_http_requests_total counter\n");
+        for ((route, status), count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "rus_http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                route, status, count
+            );
+        }
+
+        out.push_str("# HELP rus_http_request_duration_seconds HTTP request latencies by route.\n");
+        out.push_str("# TYPE rus_http_request_duration_seconds histogram\n");
+        for (route, histogram) in self.latencies.lock().unwrap().iter() {
+            let mut cumulative = 0;
+            for (i, le) in LATENCY_BUCKETS.iter().enumerate() {
+                cumulative += histogram.buckets[i];
+                let _ = writeln!(
+                    out,
+                    "rus_http_request_duration_seconds_bucket{{route=\"{}\",le=\"{}\"}} {}",
+                    route, le, cumulative
+                );
+            }
+            let _ = writeln!(
+                out,
+                "rus_http_request_duration_seconds_bucket{{route=\"{}\",le=\"+Inf\"}} {}",
+                route, histogram.count
+            );
+            let _ = writeln!(
+                out,
+                "rus_http_request_duration_seconds_sum{{route=\"{}\"}} {}",
+                route, histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                "rus_http_request_duration_seconds_count{{route=\"{}\"}} {}",
+                route, histogram.count
+            );
+        }
+
+        write_counter(
+            &mut out,
+            "rus_redirects_total",
+            "Redirects to link targets.",
+            self.redirects.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "rus_links_created_total",
+            "Created short links.",
+            self.links_created.load(Ordering::Relaxed),
+        );
+        write_counter(
+            &mut out,
+            "rus_redis_errors_total",
+            "Failed Redis commands.",
+            self.redis_errors.load(Ordering::Relaxed),
+        );
+
+        if let Some(pool_stats) = pool_stats {
+            write_gauge(
+                &mut out,
+                "rus_thread_pool_queue_depth",
+                "Jobs waiting for a free worker.",
+                pool_stats.queued(),
+            );
+            write_gauge(
+                &mut out,
+                "rus_thread_pool_busy_workers",
+                "Workers currently executing a job.",
+                pool_stats.busy(),
+            );
+            write_gauge(
+                &mut out,
+                "rus_thread_pool_workers",
+                "Size of the thread pool.",
+                pool_stats.size(),
+            );
+        }
+
+        out
+    }
+}
+
+fn write_counter(out: &mut String, name: &str, help: &str, value: u64) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+fn write_gauge(out: &mut String, name: &str, help: &str, value: usize) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+// route_label maps a request path to a route with a bounded set of values, so ids and
+// query params don't blow up the number of time series
+pub fn route_label(path: &str) -> &'static str {
+    let path = path.split('?').next().unwrap_or("");
+    if path.starts_with("/l/") {
+        return "/l/{id}";
+    }
+    if path.starts_with("/qr/") {
+        return "/qr/{id}";
+    }
+    match path {
+        "/ping" => "/ping",
+        "/healthz" => "/healthz",
+        "/readyz" => "/readyz",
+        "/hi" => "/hi",
+        "/new" => "/new",
+        "/all" => "/all",
+        "/update" => "/update",
+        "/alias" => "/alias",
+        "/history" => "/history",
+        "/rollback" => "/rollback",
+        "/trash" => "/trash",
+        "/restore" => "/restore",
+        "/delete" => "/delete",
+        "/stats" => "/stats",
+        "/analytics" => "/analytics",
+        "/metrics" => "/metrics",
+        _ => "unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{route_label, Metrics};
+    use std::time::Duration;
+
+    #[test]
+    fn test_route_label() {
+        assert_eq!(route_label("/l/abc"), "/l/{id}");
+        assert_eq!(route_label("/qr/abc?format=png"), "/qr/{id}");
+        assert_eq!(route_label("/delete?id=abc"), "/delete");
+        assert_eq!(route_label("/alias?id=abc"), "/alias");
+        assert_eq!(route_label("/history?id=abc"), "/history");
+        assert_eq!(route_label("/restore?id=abc"), "/restore");
+        assert_eq!(route_label("/ping"), "/ping");
+        assert_eq!(route_label("/wp-admin.php"), "unknown");
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_request("/l/{id}", 301, Duration::from_millis(3));
+        metrics.observe_request("/l/{id}", 301, Duration::from_millis(30));
+        metrics.observe_request("/new", 200, Duration::from_secs(20));
+        metrics.inc_redirects();
+        metrics.inc_redirects();
+        metrics.inc_redis_errors();
+
+        let out = metrics.render(None);
+        assert!(out.contains("rus_http_requests_total{route=\"/l/{id}\",status=\"301\"} 2\n"));
+        assert!(out.contains("rus_http_requests_total{route=\"/new\",status=\"200\"} 1\n"));
+        assert!(out.contains(
+            "rus_http_request_duration_seconds_bucket{route=\"/l/{id}\",le=\"0.005\"} 1\n"
+        ));
+        assert!(out.contains(
+            "rus_http_request_duration_seconds_bucket{route=\"/l/{id}\",le=\"0.05\"} 2\n"
+        ));
+        assert!(
+            out.contains("rus_http_request_duration_seconds_bucket{route=\"/new\",le=\"10\"} 0\n")
+        );
+        assert!(out
+            .contains("rus_http_request_duration_seconds_bucket{route=\"/new\",le=\"+Inf\"} 1\n"));
+        assert!(out.contains("rus_http_request_duration_seconds_count{route=\"/new\"} 1\n"));
+        assert!(out.contains("rus_redirects_total 2\n"));
+        assert!(out.contains("rus_links_created_total 0\n"));
+        assert!(out.contains("rus_redis_errors_total 1\n"));
+        assert!(!out.contains("rus_thread_pool"));
+    }
+}
@@ -5,28 +5,79 @@ use rand::{distributions::Alphanumeric, thread_rng, Rng};
 use redis::{Commands, Connection, RedisError};
 use serde_json::Value;
 use std::net::TcpStream;
+use std::sync::Arc;
 use url::Url;
 use urlencoding::decode;
 
 extern crate redis;
-use crate::{handlers::Handlers, url_record::URLRecord};
+use crate::{
+    alias_handler::alias_key,
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    link_password::{hash_password, validate_password},
+    metrics::Metrics,
+    telemetry,
+    url_record::{
+        ScheduledUrl, Target, URLRecord, Utm, Variant, MAX_SCHEDULED_URLS, MAX_TARGETS,
+        MAX_VARIANTS, REDIRECT_TYPES,
+    },
+};
 
 pub struct NewHandler {
     redis_conn: Connection,
+    metrics: Arc<Metrics>,
+    // links to these domains, and their subdomains, can't be created
+    blocked_domains: Vec<String>,
+    reserved_ids: Vec<String>,
 }
 
 impl NewHandler {
-    pub fn new(redis_conn_string: &String) -> Result<NewHandler, RedisError> {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<NewHandler, RedisError> {
         let redis_client = redis::Client::open(String::from(redis_conn_string))?;
         let redis_conn = redis_client.get_connection()?;
-        Ok(NewHandler { redis_conn })
+        Ok(NewHandler {
+            redis_conn,
+            metrics,
+            blocked_domains: vec![],
+            reserved_ids: vec![],
+        })
     }
 
-    pub fn handle_new(&mut self, stream: TcpStream, post_body: String, content_type: String) {
+    pub fn set_link_rules(&mut self, blocked_domains: &[String], reserved_ids: &[String]) {
+        self.blocked_domains = blocked_domains
+            .iter()
+            .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        self.reserved_ids = reserved_ids.to_vec();
+    }
+
+    pub fn handle_new(
+        &mut self,
+        stream: TcpStream,
+        post_body: String,
+        content_type: String,
+        actor: &str,
+    ) {
         debug!("will add new url from post body: {}", post_body);
 
-        let (url, custom_id) = match get_url_data_from_post_body(post_body, content_type) {
-            Ok((url, cid)) => (url, cid),
+        let LinkData {
+            url,
+            custom_id,
+            redirect_type,
+            expires_at,
+            forward_path,
+            utm,
+            variants,
+            targets,
+            starts_at,
+            schedule,
+            password,
+            preview,
+        } = match get_url_data_from_post_body(post_body, content_type) {
+            Ok(link_data) => link_data,
             Err(err) => {
                 debug!("new url: {}", err);
                 Handlers::respond_with_status_code(
@@ -39,46 +90,135 @@ impl NewHandler {
         };
 
         info!("will be adding new url, raw: {}", url);
-        let url = decode(url.as_str()).expect("UTF-8");
-        info!("will be adding new url, decoded: {}", url);
-
-        match Url::parse(&url) {
-            Ok(parsed_url) => {
-                debug!("new url is valid: {}", parsed_url.as_str());
+        let url = match prepare_url(&url, utm.as_ref(), &self.blocked_domains) {
+            Ok(url) => url,
+            Err(err) => {
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+        let variants = match prepare_variants(
+            variants.unwrap_or_default(),
+            utm.as_ref(),
+            &self.blocked_domains,
+        ) {
+            Ok(variants) => variants,
+            Err(err) => {
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+        let targets = match prepare_targets(
+            targets.unwrap_or_default(),
+            utm.as_ref(),
+            &self.blocked_domains,
+        ) {
+            Ok(targets) => targets,
+            Err(err) => {
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+        let schedule = match prepare_schedule(
+            schedule.unwrap_or_default(),
+            utm.as_ref(),
+            &self.blocked_domains,
+        ) {
+            Ok(schedule) => schedule,
+            Err(err) => {
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
             }
-            Err(e) => {
-                debug!("new url [{}] is NOT valid, err: {}", url, e);
+        };
+        let password_hash = match password.filter(|p| !p.is_empty()) {
+            Some(password) => match hash_password(&password) {
+                Ok(password_hash) => Some(password_hash),
+                Err(err) => {
+                    debug!("new url: {}", err);
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err,
+                    );
+                    return;
+                }
+            },
+            None => None,
+        };
+
+        if !is_valid_custom_id(&custom_id) {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                format!(
+                    "invalid id [{}], only letters, digits, '.', '_', '~' and '-' are allowed",
+                    custom_id
+                ),
+            );
+            return;
+        }
+        if is_reserved_id(&custom_id, &self.reserved_ids) {
+            debug!("custom id [{}] is reserved", custom_id);
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                format!("id [{}] is reserved", custom_id),
+            );
+            return;
+        }
+
+        if let Some(expires_at) = expires_at {
+            if expires_at <= Utc::now().timestamp() {
                 Handlers::respond_with_status_code(
                     stream,
                     StatusCode::BAD_REQUEST.as_u16(),
-                    e.to_string(),
+                    "expires_at is in the past".to_string(),
                 );
                 return;
             }
         }
 
-        let new_id: String;
-        if custom_id != "" {
-            new_id = custom_id
+        let new_id: String = if !custom_id.is_empty() {
+            custom_id
         } else {
-            new_id = thread_rng()
+            thread_rng()
                 .sample_iter(&Alphanumeric)
                 .take(10)
                 .map(char::from)
-                .collect();
-        }
+                .collect()
+        };
         info!("new valid url, id [{}] will be linked and stored", &new_id);
 
+        log_mdc::insert("link_id", &new_id);
         let url_key = format!("short_url::{}", &new_id);
 
-        let id_inuse: bool = match redis::cmd("SISMEMBER")
-            .arg("short_urls")
-            .arg(&url_key)
-            .query(&mut self.redis_conn)
-        {
+        let id_inuse: bool = match telemetry::traced("redis SISMEMBER", || {
+            redis::cmd("SISMEMBER")
+                .arg("short_urls")
+                .arg(&url_key)
+                .query(&mut self.redis_conn)
+        }) {
             Ok(val) => val,
             Err(err) => {
                 debug!("failed to execute SISMEMBER for 'short_urls': {}", err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        // the id can't be taken by a link in the trash, or an alias of another link either
+        let other_inuse = match telemetry::traced("redis EXISTS", || {
+            self.redis_conn
+                .exists::<_, i32>(&[url_key.clone(), alias_key(&new_id)])
+        }) {
+            Ok(val) => val > 0,
+            Err(err) => {
+                debug!("failed to execute EXISTS for [{}]: {}", new_id, err);
+                self.metrics.inc_redis_errors();
                 Handlers::respond_with_status_code(
                     stream,
                     StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
@@ -87,7 +227,7 @@ impl NewHandler {
                 return;
             }
         };
-        if id_inuse {
+        if id_inuse || other_inuse {
             debug!(
                 "error, url with key {} already exists, skipping add",
                 new_id
@@ -105,18 +245,37 @@ impl NewHandler {
             url: url.to_string(),
             timestamp: Utc::now().timestamp(),
             hits: 0,
+            redirect_type,
+            expires_at,
+            forward_path: forward_path.unwrap_or(false),
+            utm,
+            variants,
+            targets,
+            starts_at,
+            schedule,
+            password_hash,
+            preview: preview.unwrap_or(false),
+            aliases: vec![],
+            version: 1,
+            deleted_at: None,
         };
 
         let url_record_json = url_record.to_json();
-        println!("++ storing new url record: {}", url_record_json);
+        debug!(
+            "++ storing new url record: {}",
+            url_record.to_public_value()
+        );
 
-        let _: () = match self.redis_conn.set(&url_key, String::from(url_record_json)) {
+        let _: () = match telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, url_record_json)
+        }) {
             Ok(val) => val,
             Err(err) => {
                 debug!(
                     "failed to execute SET for new url key [{}]: {}",
                     url_key, err
                 );
+                self.metrics.inc_redis_errors();
                 Handlers::respond_with_status_code(
                     stream,
                     StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
@@ -126,10 +285,13 @@ impl NewHandler {
             }
         };
 
-        let _: () = match self.redis_conn.sadd("short_urls", &url_key) {
+        let _: () = match telemetry::traced("redis SADD", || {
+            self.redis_conn.sadd("short_urls", &url_key)
+        }) {
             Ok(val) => val,
             Err(err) => {
                 debug!("failed to execute SADD for 'short_urls': {}", err);
+                self.metrics.inc_redis_errors();
                 Handlers::respond_with_status_code(
                     stream,
                     StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
@@ -139,94 +301,482 @@ impl NewHandler {
             }
         };
 
+        let entry = HistoryEntry::new(&url_record, actor, "create");
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the creation of [{}]: {}", new_id, err);
+            self.metrics.inc_redis_errors();
+        }
+
         debug!("new url [{}] has been saved, path: /l/{}", url, new_id);
-        Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), format!("{}", new_id));
+        Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), new_id);
     }
 }
 
+// validate_variants expects either none, or at least two variants with a positive weight
+fn validate_variants(variants: &[Variant]) -> Result<(), String> {
+    if variants.len() == 1 {
+        return Err("a split link needs at least 2 variants".to_string());
+    }
+    if variants.len() > MAX_VARIANTS {
+        return Err(format!(
+            "a split link can have at most {} variants",
+            MAX_VARIANTS
+        ));
+    }
+    if variants.iter().any(|v| v.weight == 0) {
+        return Err("variant weights must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// prepare_variants checks and prepares the urls of the variants the same way as the url of
+// the link
+pub fn prepare_variants(
+    variants: Vec<Variant>,
+    utm: Option<&Utm>,
+    blocked_domains: &[String],
+) -> Result<Vec<Variant>, String> {
+    variants
+        .into_iter()
+        .map(|variant| {
+            Ok(Variant {
+                url: prepare_url(&variant.url, utm, blocked_domains)?,
+                weight: variant.weight,
+            })
+        })
+        .collect()
+}
+
+// validate_targets expects each target to be for a platform and/or a language
+fn validate_targets(targets: &[Target]) -> Result<(), String> {
+    if targets.len() > MAX_TARGETS {
+        return Err(format!("a link can have at most {} targets", MAX_TARGETS));
+    }
+    targets.iter().try_for_each(Target::validate)
+}
+
+// prepare_targets checks and prepares the urls of the targets the same way as the url of the
+// link
+pub fn prepare_targets(
+    targets: Vec<Target>,
+    utm: Option<&Utm>,
+    blocked_domains: &[String],
+) -> Result<Vec<Target>, String> {
+    targets
+        .into_iter()
+        .map(|target| {
+            Ok(Target {
+                url: prepare_url(&target.url, utm, blocked_domains)?,
+                ..target
+            })
+        })
+        .collect()
+}
+
+// validate_schedule expects the scheduled urls to start at distinct times
+fn validate_schedule(schedule: &[ScheduledUrl]) -> Result<(), String> {
+    if schedule.len() > MAX_SCHEDULED_URLS {
+        return Err(format!(
+            "a link can have at most {} scheduled urls",
+            MAX_SCHEDULED_URLS
+        ));
+    }
+    for (i, scheduled_url) in schedule.iter().enumerate() {
+        if scheduled_url.from < 0 {
+            return Err("scheduled url from can't be negative".to_string());
+        }
+        if schedule[..i].iter().any(|s| s.from == scheduled_url.from) {
+            return Err(format!(
+                "more than one url is scheduled from {}",
+                scheduled_url.from
+            ));
+        }
+    }
+    Ok(())
+}
+
+// prepare_schedule checks and prepares the scheduled urls the same way as the url of the link
+pub fn prepare_schedule(
+    schedule: Vec<ScheduledUrl>,
+    utm: Option<&Utm>,
+    blocked_domains: &[String],
+) -> Result<Vec<ScheduledUrl>, String> {
+    schedule
+        .into_iter()
+        .map(|scheduled_url| {
+            Ok(ScheduledUrl {
+                url: prepare_url(&scheduled_url.url, utm, blocked_domains)?,
+                ..scheduled_url
+            })
+        })
+        .collect()
+}
+
+// prepare_url checks the url of a link, and adds the campaign params to it
+pub fn prepare_url(
+    url: &str,
+    utm: Option<&Utm>,
+    blocked_domains: &[String],
+) -> Result<String, String> {
+    let url = check_url(url, blocked_domains)?;
+    match utm {
+        Some(utm) => utm.apply_to(&url),
+        None => Ok(url),
+    }
+}
+
+// check_url decodes the url of a link and checks it can be linked to, the error is meant for the
+// client
+pub fn check_url(url: &str, blocked_domains: &[String]) -> Result<String, String> {
+    let url = decode(url).map_err(|e| e.to_string())?;
+    info!("will be adding new url, decoded: {}", url);
+
+    match Url::parse(&url) {
+        Ok(parsed_url) => {
+            debug!("new url is valid: {}", parsed_url.as_str());
+            if is_blocked_domain(parsed_url.host_str(), blocked_domains) {
+                debug!("new url [{}] points to a blocked domain", url);
+                return Err("domain not allowed".to_string());
+            }
+        }
+        Err(e) => {
+            debug!("new url [{}] is NOT valid, err: {}", url, e);
+            return Err(e.to_string());
+        }
+    }
+    Ok(url.to_string())
+}
+
+// is_valid_custom_id allows only the url safe characters in ids (and aliases), as the /l/ path
+// isn't percent-decoded and the id goes as is into the Path of cookies; this also tells the id
+// apart from a forwarded path or query, and from a request for its preview (a trailing +)
+pub fn is_valid_custom_id(custom_id: &str) -> bool {
+    custom_id
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '~' | '-'))
+}
+
+// is_reserved_id tells whether the id is one of the reserved ones, ignoring case
+pub fn is_reserved_id(id: &str, reserved_ids: &[String]) -> bool {
+    reserved_ids.iter().any(|r| r.eq_ignore_ascii_case(id))
+}
+
+// is_blocked_url tells whether the (already checked) url points to one of the blocked domains
+pub fn is_blocked_url(url: &str, blocked_domains: &[String]) -> bool {
+    Url::parse(url).is_ok_and(|url| is_blocked_domain(url.host_str(), blocked_domains))
+}
+
+// is_blocked_domain tells whether the host is one of the (lowercase) blocked domains, or their
+// subdomain
+fn is_blocked_domain(host: Option<&str>, blocked_domains: &[String]) -> bool {
+    let host = match host {
+        Some(h) => h.trim_end_matches('.').to_ascii_lowercase(),
+        None => return false,
+    };
+    blocked_domains.iter().any(|domain| {
+        host == *domain
+            || host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| sub.ends_with('.'))
+    })
+}
+
+// LinkData is what's sent in the post body when creating or updating a link
+#[derive(Debug, Default, PartialEq)]
+pub struct LinkData {
+    // empty when not given
+    pub url: String,
+    pub custom_id: String,
+    pub redirect_type: Option<u16>,
+    // unix timestamp, 0 removes the expiry of an updated link
+    pub expires_at: Option<i64>,
+    pub forward_path: Option<bool>,
+    // campaign parameters merged into the url, only taken from json bodies
+    pub utm: Option<Utm>,
+    // destinations of a split link, only taken from json bodies; an empty list turns an
+    // updated link back into a regular one
+    pub variants: Option<Vec<Variant>>,
+    // destinations by client platform and language, only taken from json bodies; an empty
+    // list removes them from an updated link
+    pub targets: Option<Vec<Target>>,
+    // unix timestamp, 0 makes an updated link active right away
+    pub starts_at: Option<i64>,
+    // destinations switching over time, only taken from json bodies; an empty list removes
+    // them from an updated link
+    pub schedule: Option<Vec<ScheduledUrl>>,
+    // visitors have to enter it before being redirected; an empty one removes it from an
+    // updated link
+    pub password: Option<String>,
+    pub preview: Option<bool>,
+}
+
+// get_url_data_from_post_body returns the link data from the post body of a new link, which
+// requires the url
 fn get_url_data_from_post_body(
     post_body: String,
     content_type: String,
-) -> Result<(String, String), String> {
-    match content_type.as_str() {
-        "application/json" => get_url_data_from_json_body(post_body),
-        "application/x-www-form-urlencoded" => get_url_data_from_form_urlencoded_body(post_body),
-        _ => Err("Invalid content_type".to_string()),
+) -> Result<LinkData, String> {
+    let mut link_data = get_link_data_from_post_body(post_body, content_type)?;
+    // a split link goes to its first variant wherever a single url is expected
+    if link_data.url.is_empty() {
+        if let Some(variant) = link_data.variants.as_ref().and_then(|v| v.first()) {
+            link_data.url = variant.url.clone();
+        }
+    }
+    if link_data.url.is_empty() {
+        return Err("url param not found".to_string());
+    }
+    Ok(link_data)
+}
+
+pub fn get_link_data_from_post_body(
+    post_body: String,
+    content_type: String,
+) -> Result<LinkData, String> {
+    let link_data = match content_type.as_str() {
+        "application/json" => get_link_data_from_json_body(post_body)?,
+        "application/x-www-form-urlencoded" => get_link_data_from_form_urlencoded_body(post_body)?,
+        _ => return Err("Invalid content_type".to_string()),
+    };
+    if let Some(redirect_type) = link_data.redirect_type {
+        if !REDIRECT_TYPES.contains(&redirect_type) {
+            return Err(format!(
+                "invalid redirect_type {}, expected one of {:?}",
+                redirect_type, REDIRECT_TYPES
+            ));
+        }
+    }
+    if let Some(utm) = &link_data.utm {
+        utm.validate()?;
+    }
+    if link_data.expires_at.is_some_and(|t| t < 0) {
+        return Err("expires_at can't be negative".to_string());
+    }
+    if link_data.starts_at.is_some_and(|t| t < 0) {
+        return Err("starts_at can't be negative".to_string());
+    }
+    if let (Some(starts_at), Some(expires_at)) = (link_data.starts_at, link_data.expires_at) {
+        if starts_at > 0 && expires_at > 0 && starts_at >= expires_at {
+            return Err("starts_at must be before expires_at".to_string());
+        }
+    }
+    if let Some(variants) = &link_data.variants {
+        validate_variants(variants)?;
+    }
+    if let Some(targets) = &link_data.targets {
+        validate_targets(targets)?;
+    }
+    if let Some(schedule) = &link_data.schedule {
+        validate_schedule(schedule)?;
     }
+    if let Some(password) = link_data.password.as_ref().filter(|p| !p.is_empty()) {
+        validate_password(password)?;
+    }
+    Ok(link_data)
 }
 
-fn get_url_data_from_json_body(json_str: String) -> Result<(String, String), String> {
+fn get_link_data_from_json_body(json_str: String) -> Result<LinkData, String> {
     let parsed_json: Value =
         serde_json::from_str(&json_str).map_err(|_| "Failed to parse JSON".to_string())?;
+    if !parsed_json.is_object() {
+        return Err("JSON body is not an object".to_string());
+    }
 
-    let url = parsed_json
-        .get("url")
-        .ok_or("Missing url field in JSON".to_string())?
-        .as_str()
-        .ok_or("url field is not a string".to_string())?
-        .to_string();
+    let url = match parsed_json.get("url") {
+        Some(url) => url
+            .as_str()
+            .ok_or("url field is not a string".to_string())?
+            .to_string(),
+        None => String::new(),
+    };
 
-    let id = parsed_json
+    let custom_id = parsed_json
         .get("cid")
         .and_then(Value::as_str)
         .unwrap_or("")
         .to_string();
 
-    Ok((url, id))
+    let redirect_type = match parsed_json.get("redirect_type") {
+        Some(redirect_type) => Some(
+            redirect_type
+                .as_u64()
+                .and_then(|t| u16::try_from(t).ok())
+                .ok_or("redirect_type field is not a status code".to_string())?,
+        ),
+        None => None,
+    };
+
+    let expires_at = match parsed_json.get("expires_at") {
+        Some(expires_at) => Some(
+            expires_at
+                .as_i64()
+                .ok_or("expires_at field is not a timestamp".to_string())?,
+        ),
+        None => None,
+    };
+
+    let preview = match parsed_json.get("preview") {
+        Some(preview) => Some(
+            preview
+                .as_bool()
+                .ok_or("preview field is not a boolean".to_string())?,
+        ),
+        None => None,
+    };
+
+    let starts_at = match parsed_json.get("starts_at") {
+        Some(starts_at) => Some(
+            starts_at
+                .as_i64()
+                .ok_or("starts_at field is not a timestamp".to_string())?,
+        ),
+        None => None,
+    };
+
+    let forward_path = match parsed_json.get("forward_path") {
+        Some(forward_path) => Some(
+            forward_path
+                .as_bool()
+                .ok_or("forward_path field is not a boolean".to_string())?,
+        ),
+        None => None,
+    };
+
+    let utm = match parsed_json.get("utm") {
+        Some(utm) => Some(
+            serde_json::from_value::<Utm>(utm.clone())
+                .map_err(|e| format!("invalid utm: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let variants = match parsed_json.get("variants") {
+        Some(variants) => Some(
+            serde_json::from_value::<Vec<Variant>>(variants.clone())
+                .map_err(|e| format!("invalid variants: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let targets = match parsed_json.get("targets") {
+        Some(targets) => Some(
+            serde_json::from_value::<Vec<Target>>(targets.clone())
+                .map_err(|e| format!("invalid targets: {}", e))?,
+        ),
+        None => None,
+    };
+
+    let password = match parsed_json.get("password") {
+        Some(password) => Some(
+            password
+                .as_str()
+                .ok_or("password field is not a string".to_string())?
+                .to_string(),
+        ),
+        None => None,
+    };
+
+    let schedule = match parsed_json.get("schedule") {
+        Some(schedule) => Some(
+            serde_json::from_value::<Vec<ScheduledUrl>>(schedule.clone())
+                .map_err(|e| format!("invalid schedule: {}", e))?,
+        ),
+        None => None,
+    };
+
+    Ok(LinkData {
+        url,
+        custom_id,
+        redirect_type,
+        expires_at,
+        forward_path,
+        utm,
+        variants,
+        targets,
+        starts_at,
+        schedule,
+        password,
+        preview,
+    })
 }
 
-// get_url_data_from_post_body returns found url and custom ID from the post body
-// - post_body expected form is: url=http://blabla&cid=some
-fn get_url_data_from_form_urlencoded_body(post_body: String) -> Result<(String, String), String> {
-    let mut url = String::from("");
-    let mut custom_id = String::from("");
+// get_link_data_from_form_urlencoded_body returns the link data from the post body
+// - post_body expected form is:
+//   url=http://blabla&cid=some&redirect_type=302&starts_at=1690000000&expires_at=1700000000&forward_path=true&preview=false
+fn get_link_data_from_form_urlencoded_body(post_body: String) -> Result<LinkData, String> {
+    let mut link_data = LinkData::default();
 
     let post_body_parts: Vec<&str> = post_body.split_terminator("&").collect();
-    if post_body_parts.len() == 0 {
+    if post_body_parts.is_empty() {
         return Err("post body invalid (0 parts)".to_string());
     }
 
-    let first_param = post_body_parts[0];
-    let first_param_parts: Vec<&str> = first_param.split_terminator("=").collect();
-    if first_param_parts.len() != 2 {
-        return Err(format!(
-            "invalid parameter: {}, no value found",
-            first_param_parts[0]
-        ));
-    }
-    match first_param_parts[0] {
-        "url" => url = first_param_parts[1].to_string(),
-        "cid" => custom_id = first_param_parts[1].to_string(),
-        inv_param => debug!("invalid new link param: {}", inv_param),
-    }
-
-    if post_body_parts.len() < 2 {
-        if url == "" {
-            return Err("url param not found".to_string());
+    for param in post_body_parts {
+        let param_parts: Vec<&str> = param.split_terminator("=").collect();
+        if param_parts.len() != 2 {
+            return Err(format!(
+                "invalid parameter: {}, no value found",
+                param_parts.first().unwrap_or(&"")
+            ));
+        }
+        match param_parts[0] {
+            "url" => link_data.url = param_parts[1].to_string(),
+            "cid" => link_data.custom_id = param_parts[1].to_string(),
+            "redirect_type" => {
+                link_data.redirect_type = Some(
+                    param_parts[1]
+                        .parse::<u16>()
+                        .map_err(|_| format!("invalid redirect_type: {}", param_parts[1]))?,
+                )
+            }
+            "starts_at" => {
+                link_data.starts_at = Some(
+                    param_parts[1]
+                        .parse::<i64>()
+                        .map_err(|_| format!("invalid starts_at: {}", param_parts[1]))?,
+                )
+            }
+            "expires_at" => {
+                link_data.expires_at = Some(
+                    param_parts[1]
+                        .parse::<i64>()
+                        .map_err(|_| format!("invalid expires_at: {}", param_parts[1]))?,
+                )
+            }
+            "password" => {
+                link_data.password = Some(
+                    decode(&param_parts[1].replace('+', " "))
+                        .map_err(|_| "invalid password encoding".to_string())?
+                        .into_owned(),
+                )
+            }
+            "forward_path" => {
+                link_data.forward_path = Some(
+                    param_parts[1]
+                        .parse::<bool>()
+                        .map_err(|_| format!("invalid forward_path: {}", param_parts[1]))?,
+                )
+            }
+            "preview" => {
+                link_data.preview = Some(
+                    param_parts[1]
+                        .parse::<bool>()
+                        .map_err(|_| format!("invalid preview: {}", param_parts[1]))?,
+                )
+            }
+            inv_param => debug!("invalid new link param: {}", inv_param),
         }
-        return Ok((url, custom_id));
-    }
-
-    let second_param = post_body_parts[1];
-    let second_param_parts: Vec<&str> = second_param.split_terminator("=").collect();
-    if second_param_parts.len() != 2 {
-        return Err(format!(
-            "invalid parameter: {}, no value found",
-            second_param_parts[0]
-        ));
-    }
-    match second_param_parts[0] {
-        "url" => url = second_param_parts[1].to_string(),
-        "cid" => custom_id = second_param_parts[1].to_string(),
-        inv_param => debug!("invalid new link param: {}", inv_param),
     }
 
-    return Ok((url, custom_id));
+    Ok(link_data)
 }
 
 #[cfg(test)]
 mod tests {
-    use super::get_url_data_from_post_body;
+    use super::{
+        get_link_data_from_post_body, get_url_data_from_post_body, is_blocked_domain,
+        is_blocked_url, is_valid_custom_id, LinkData,
+    };
 
     fn test_get_url_data_case(
         post_body: &str,
@@ -236,7 +786,7 @@ mod tests {
     ) -> Result<(), String> {
         let (url, cid) =
             match get_url_data_from_post_body(post_body.to_string(), content_type.to_string()) {
-                Ok((url, cid)) => (url, cid),
+                Ok(link_data) => (link_data.url, link_data.custom_id),
                 Err(err) => {
                     return Err(err);
                 }
@@ -286,7 +836,7 @@ mod tests {
             ),
         ]
         .iter()
-        .try_for_each(|(pb, ct, url, cid)| test_get_url_data_case(*pb, *ct, *url, *cid))?;
+        .try_for_each(|(pb, ct, url, cid)| test_get_url_data_case(pb, ct, url, cid))?;
 
         Ok(())
     }
@@ -308,7 +858,7 @@ mod tests {
                 (*pb).to_string(),
                 "application/x-www-form-urlencoded".to_string(),
             ) {
-                Ok((url, cid)) => (url, cid),
+                Ok(link_data) => (link_data.url, link_data.custom_id),
                 Err(_) => return Ok(()),
             };
             Err(format!(
@@ -334,7 +884,7 @@ mod tests {
                 (*pb).to_string(),
                 "application/json".to_string(),
             ) {
-                Ok((url, cid)) => (url, cid),
+                Ok(link_data) => (link_data.url, link_data.custom_id),
                 Err(_) => return Ok(()),
             };
             Err(format!(
@@ -345,4 +895,276 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_get_link_data_utm() {
+        let json = "application/json".to_string();
+        let link_data = get_url_data_from_post_body(
+            r#"{"url":"http://2beens.xyz","utm":{"source":"newsletter","campaign":"spring"}}"#
+                .to_string(),
+            json.clone(),
+        )
+        .unwrap();
+        let utm = link_data.utm.unwrap();
+        assert_eq!(utm.source, "newsletter");
+        assert_eq!(utm.campaign, Some("spring".to_string()));
+
+        [
+            r#"{"url":"http://2beens.xyz","utm":{"campaign":"spring"}}"#,
+            r#"{"url":"http://2beens.xyz","utm":{"source":""}}"#,
+            r#"{"url":"http://2beens.xyz","utm":{"source":"a","camp":"spring"}}"#,
+            r#"{"url":"http://2beens.xyz","utm":"utm_source=a"}"#,
+        ]
+        .iter()
+        .for_each(|body| {
+            assert!(
+                get_url_data_from_post_body(body.to_string(), json.clone()).is_err(),
+                "{}",
+                body
+            )
+        });
+    }
+
+    #[test]
+    fn test_get_link_data_variants() {
+        let json = "application/json".to_string();
+        let link_data = get_url_data_from_post_body(
+            r#"{"variants":[{"url":"http://a.xyz","weight":70},{"url":"http://b.xyz","weight":30}]}"#
+                .to_string(),
+            json.clone(),
+        )
+        .unwrap();
+        assert_eq!(link_data.url, "http://a.xyz");
+        assert_eq!(link_data.variants.map(|v| v.len()), Some(2));
+
+        // an update can drop the variants
+        assert_eq!(
+            get_link_data_from_post_body(r#"{"variants":[]}"#.to_string(), json.clone())
+                .map(|d| d.variants),
+            Ok(Some(vec![]))
+        );
+
+        [
+            r#"{"variants":[{"url":"http://a.xyz","weight":1}]}"#,
+            r#"{"variants":[{"url":"http://a.xyz","weight":1},{"url":"http://b.xyz","weight":0}]}"#,
+            r#"{"variants":[{"url":"http://a.xyz"},{"url":"http://b.xyz"}]}"#,
+            r#"{"variants":"http://a.xyz"}"#,
+        ]
+        .iter()
+        .for_each(|body| {
+            assert!(
+                get_link_data_from_post_body(body.to_string(), json.clone()).is_err(),
+                "{}",
+                body
+            )
+        });
+    }
+
+    #[test]
+    fn test_get_link_data_targets() {
+        let json = "application/json".to_string();
+        let link_data = get_url_data_from_post_body(
+            r#"{"url":"https://2beens.xyz/app","targets":[{"platform":"ios","url":"https://apps.apple.com/app/id1"},{"language":"de","url":"https://2beens.xyz/de"}]}"#
+                .to_string(),
+            json.clone(),
+        )
+        .unwrap();
+        assert_eq!(link_data.targets.map(|t| t.len()), Some(2));
+
+        for body in [
+            r#"{"url":"https://2beens.xyz","targets":[{"url":"https://2beens.xyz/x"}]}"#,
+            r#"{"url":"https://2beens.xyz","targets":[{"platform":"symbian","url":"https://2beens.xyz/x"}]}"#,
+            r#"{"url":"https://2beens.xyz","targets":[{"platform":"ios"}]}"#,
+            r#"{"url":"https://2beens.xyz","targets":{"ios":"https://2beens.xyz/x"}}"#,
+        ] {
+            assert!(
+                get_link_data_from_post_body(body.to_string(), json.clone()).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_valid_custom_id() {
+        assert!(is_valid_custom_id("docs"));
+        assert!(is_valid_custom_id(""));
+        assert!(!is_valid_custom_id("docs/faq"));
+        assert!(!is_valid_custom_id("docs?x=1"));
+        assert!(!is_valid_custom_id("docs#top"));
+        assert!(!is_valid_custom_id("docs+"));
+        assert!(!is_valid_custom_id("c++docs"));
+        assert!(is_valid_custom_id("Spring-Sale_2024.v2~"));
+        for id in [
+            "spring sale",
+            "50%off",
+            "a;Path=/",
+            "tab\tid",
+            "naïve",
+            "a\"b",
+        ] {
+            assert!(!is_valid_custom_id(id), "{}", id);
+        }
+    }
+
+    #[test]
+    fn test_is_blocked_domain() {
+        let blocked = vec!["evil.com".to_string(), "spam.co.uk".to_string()];
+        assert!(is_blocked_domain(Some("evil.com"), &blocked));
+        assert!(is_blocked_domain(Some("www.EVIL.com"), &blocked));
+        assert!(is_blocked_domain(Some("evil.com."), &blocked));
+        assert!(is_blocked_domain(Some("a.b.spam.co.uk"), &blocked));
+        assert!(!is_blocked_domain(Some("notevil.com"), &blocked));
+        assert!(!is_blocked_domain(Some("evil.com.example.org"), &blocked));
+        assert!(!is_blocked_domain(None, &blocked));
+        assert!(!is_blocked_domain(Some("evil.com"), &[]));
+
+        assert!(is_blocked_url("https://www.evil.com/x", &blocked));
+        assert!(!is_blocked_url("https://2beens.xyz", &blocked));
+        assert!(!is_blocked_url("not a url", &blocked));
+    }
+
+    #[test]
+    fn test_get_link_data_redirect_type() {
+        let form = "application/x-www-form-urlencoded".to_string();
+        let json = "application/json".to_string();
+        assert_eq!(
+            get_link_data_from_post_body(
+                "url=http://2beens.xyz&redirect_type=302".to_string(),
+                form.clone()
+            ),
+            Ok(LinkData {
+                url: "http://2beens.xyz".to_string(),
+                custom_id: "".to_string(),
+                redirect_type: Some(302),
+                expires_at: None,
+                forward_path: None,
+                utm: None,
+                variants: None,
+                targets: None,
+                starts_at: None,
+                schedule: None,
+                password: None,
+                preview: None,
+            })
+        );
+        // updates don't need the url
+        assert_eq!(
+            get_link_data_from_post_body(r#"{"redirect_type": 307}"#.to_string(), json.clone()),
+            Ok(LinkData {
+                redirect_type: Some(307),
+                ..LinkData::default()
+            })
+        );
+        assert!(
+            get_url_data_from_post_body(r#"{"redirect_type": 307}"#.to_string(), json.clone())
+                .is_err()
+        );
+
+        assert!(
+            get_link_data_from_post_body("url=a&redirect_type=200".to_string(), form.clone())
+                .is_err()
+        );
+        assert!(get_link_data_from_post_body("redirect_type=abc".to_string(), form).is_err());
+        assert!(get_link_data_from_post_body(
+            r#"{"redirect_type": "302"}"#.to_string(),
+            json.clone()
+        )
+        .is_err());
+        assert!(get_link_data_from_post_body("[1]".to_string(), json).is_err());
+    }
+
+    #[test]
+    fn test_get_link_data_expires_at() {
+        let form = "application/x-www-form-urlencoded".to_string();
+        let json = "application/json".to_string();
+        assert_eq!(
+            get_link_data_from_post_body(
+                "url=http://2beens.xyz&expires_at=1700000000".to_string(),
+                form.clone()
+            )
+            .map(|d| d.expires_at),
+            Ok(Some(1700000000))
+        );
+        assert_eq!(
+            get_link_data_from_post_body(r#"{"expires_at": 0}"#.to_string(), json.clone())
+                .map(|d| d.expires_at),
+            Ok(Some(0))
+        );
+        assert!(get_link_data_from_post_body("expires_at=-1".to_string(), form.clone()).is_err());
+        assert!(get_link_data_from_post_body("expires_at=soon".to_string(), form).is_err());
+        assert!(
+            get_link_data_from_post_body(r#"{"expires_at": "1700000000"}"#.to_string(), json)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_get_link_data_schedule() {
+        let form = "application/x-www-form-urlencoded".to_string();
+        let json = "application/json".to_string();
+        assert_eq!(
+            get_link_data_from_post_body(
+                "url=http://2beens.xyz&starts_at=1690000000&expires_at=1700000000".to_string(),
+                form.clone()
+            )
+            .map(|d| d.starts_at),
+            Ok(Some(1690000000))
+        );
+        let link_data = get_url_data_from_post_body(
+            r#"{"url":"https://2beens.xyz/event","schedule":[{"from":1700000000,"url":"https://2beens.xyz/recording"}]}"#
+                .to_string(),
+            json.clone(),
+        )
+        .unwrap();
+        assert_eq!(link_data.schedule.map(|s| s.len()), Some(1));
+
+        for (body, content_type) in [
+            ("url=http://2beens.xyz&starts_at=-1", &form),
+            (
+                "url=http://2beens.xyz&starts_at=1700000000&expires_at=1690000000",
+                &form,
+            ),
+            (
+                r#"{"url":"https://2beens.xyz","starts_at":"tomorrow"}"#,
+                &json,
+            ),
+            (
+                r#"{"url":"https://2beens.xyz","schedule":[{"from":1,"url":"https://a.xyz"},{"from":1,"url":"https://b.xyz"}]}"#,
+                &json,
+            ),
+            (
+                r#"{"url":"https://2beens.xyz","schedule":[{"at":1,"url":"https://a.xyz"}]}"#,
+                &json,
+            ),
+        ] {
+            assert!(
+                get_link_data_from_post_body(body.to_string(), content_type.clone()).is_err(),
+                "{}",
+                body
+            );
+        }
+    }
+
+    #[test]
+    fn test_get_link_data_password() {
+        let form = "application/x-www-form-urlencoded".to_string();
+        let json = "application/json".to_string();
+        assert_eq!(
+            get_link_data_from_post_body(
+                "url=http://2beens.xyz&password=open+sesame%21".to_string(),
+                form.clone()
+            )
+            .map(|d| d.password),
+            Ok(Some("open sesame!".to_string()))
+        );
+        // an update can remove the password
+        assert_eq!(
+            get_link_data_from_post_body(r#"{"password":""}"#.to_string(), json.clone())
+                .map(|d| d.password),
+            Ok(Some("".to_string()))
+        );
+        assert!(get_link_data_from_post_body("url=a&password=abc".to_string(), form).is_err());
+        assert!(get_link_data_from_post_body(r#"{"password":1234}"#.to_string(), json).is_err());
+    }
 }
@@ -0,0 +1,80 @@
+use crate::handlers::escape_html;
+use chrono::DateTime;
+use url::Url;
+
+// DEFAULT_TEMPLATE is the preview page used unless links.preview_template is set
+pub const DEFAULT_TEMPLATE: &str = include_str!("templates/preview.html");
+
+// render_preview fills in the preview page template, where {id}, {url}, {href}, {created_at}
+// and {hits} are replaced with the escaped values; {href} is the url only when it's a web one,
+// so the page can't be made to run scripts
+pub fn render_preview(
+    template: &str,
+    url_id: &str,
+    url: &str,
+    created_at: i64,
+    hits: i32,
+) -> String {
+    let href = match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => url,
+        _ => "#",
+    };
+    // links from before creation dates were stored have a timestamp of 0
+    let created_at = DateTime::from_timestamp(created_at, 0)
+        .filter(|_| created_at > 0)
+        .map_or("unknown".to_string(), |t| {
+            t.format("%Y-%m-%d %H:%M UTC").to_string()
+        });
+
+    // a single pass, so values containing placeholders aren't replaced again
+    let mut page = String::with_capacity(template.len() + url.len() * 2);
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        page.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = match rest.find('}').map(|end| &rest[1..end]) {
+            Some("id") => escape_html(url_id),
+            Some("url") => escape_html(url),
+            Some("href") => escape_html(href),
+            Some("created_at") => created_at.clone(),
+            Some("hits") => hits.to_string(),
+            _ => {
+                page.push('{');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        page.push_str(&value);
+        rest = &rest[rest.find('}').unwrap_or(0) + 1..];
+    }
+    page.push_str(rest);
+    page
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_preview, DEFAULT_TEMPLATE};
+
+    #[test]
+    fn test_render_preview() {
+        let template = "<a href=\"{href}\">{url}</a> {id} {created_at} {hits} {other} {";
+        assert_eq!(
+            render_preview(
+                template,
+                "abc",
+                "https://2beens.xyz/?a=1&b=\"><script>",
+                1700000000,
+                42
+            ),
+            "<a href=\"https://2beens.xyz/?a=1&amp;b=&quot;&gt;&lt;script&gt;\">https://2beens.xyz/?a=1&amp;b=&quot;&gt;&lt;script&gt;</a> abc 2023-11-14 22:13 UTC 42 {other} {"
+        );
+        assert_eq!(
+            render_preview(template, "{url}", "javascript:alert(1)", 0, 0),
+            "<a href=\"#\">javascript:alert(1)</a> {url} unknown 0 {other} {"
+        );
+
+        let page = render_preview(DEFAULT_TEMPLATE, "abc", "https://2beens.xyz", 1, 7);
+        assert!(page.contains("<a href=\"https://2beens.xyz\""));
+        assert!(page.contains("Hits: 7"));
+    }
+}
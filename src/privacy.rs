@@ -0,0 +1,89 @@
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+// IpAnonymization decides what is left of a client ip before it is stored with a click
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpAnonymization {
+    // store the ip as is
+    None,
+    // zero the host part of the ip, leaving /24 for ipv4 and /48 for ipv6
+    Truncate,
+    // store a salted hash of the ip, clicks from one ip can still be correlated
+    Hash { salt: String },
+}
+
+impl IpAnonymization {
+    // parse expects one of: none, truncate, hash
+    pub fn parse(mode: &str, salt: &str) -> Result<IpAnonymization, String> {
+        match mode.trim() {
+            "none" => Ok(IpAnonymization::None),
+            "truncate" => Ok(IpAnonymization::Truncate),
+            "hash" => {
+                if salt.is_empty() {
+                    return Err("ip hashing requires a salt".to_string());
+                }
+                Ok(IpAnonymization::Hash {
+                    salt: salt.to_string(),
+                })
+            }
+            other => Err(format!("unknown ip anonymization mode: {}", other)),
+        }
+    }
+
+    pub fn anonymize(&self, ip: IpAddr) -> String {
+        match self {
+            IpAnonymization::None => ip.to_string(),
+            IpAnonymization::Truncate => truncate_ip(ip).to_string(),
+            IpAnonymization::Hash { salt } => hash_ip(ip, salt),
+        }
+    }
+}
+
+fn truncate_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => IpAddr::V6(Ipv6Addr::from(u128::from(ip) & (u128::MAX << 80))),
+    }
+}
+
+fn hash_ip(ip: IpAddr, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(ip.to_string().as_bytes());
+    // half of the digest is more than enough to tell ips apart
+    hasher.finalize()[..16]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IpAnonymization;
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_anonymize() {
+        let ipv4: IpAddr = "203.0.113.77".parse().unwrap();
+        let ipv6: IpAddr = "2001:db8:cafe:1234::17".parse().unwrap();
+
+        assert_eq!(IpAnonymization::None.anonymize(ipv4), "203.0.113.77");
+        assert_eq!(IpAnonymization::Truncate.anonymize(ipv4), "203.0.113.0");
+        assert_eq!(IpAnonymization::Truncate.anonymize(ipv6), "2001:db8:cafe::");
+
+        let hash = IpAnonymization::parse("hash", "pepper").unwrap();
+        let hashed = hash.anonymize(ipv4);
+        assert_eq!(hashed.len(), 32);
+        assert_eq!(hashed, hash.anonymize(ipv4));
+        assert_ne!(hashed, hash.anonymize(ipv6));
+        assert_ne!(
+            hashed,
+            IpAnonymization::parse("hash", "salt")
+                .unwrap()
+                .anonymize(ipv4)
+        );
+
+        assert!(IpAnonymization::parse("hash", "").is_err());
+        assert!(IpAnonymization::parse("scramble", "").is_err());
+    }
+}
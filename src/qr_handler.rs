@@ -0,0 +1,332 @@
+use http::StatusCode;
+use log::debug;
+use qrcode::{Color, EcLevel, QrCode};
+use redis::{Commands, Connection, RedisError};
+use std::fmt::Write;
+use std::net::TcpStream;
+use std::sync::Arc;
+use url::form_urlencoded;
+
+extern crate redis;
+use crate::{alias_handler::alias_key, handlers::Handlers, metrics::Metrics, telemetry};
+
+// limits of the query params, the size is in pixels and the margin in modules
+const DEFAULT_SIZE: u32 = 256;
+const MIN_SIZE: u32 = 32;
+const MAX_SIZE: u32 = 2048;
+const DEFAULT_MARGIN: u32 = 4;
+const MAX_MARGIN: u32 = 20;
+
+pub struct QrHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+    // base of the short urls in the codes, e.g. https://s.example.com
+    public_url: String,
+}
+
+impl QrHandler {
+    pub fn new(redis_conn_string: &String, metrics: Arc<Metrics>) -> Result<QrHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(QrHandler {
+            redis_conn,
+            metrics,
+            public_url: String::new(),
+        })
+    }
+
+    pub fn set_public_url(&mut self, public_url: &str) {
+        self.public_url = public_url.trim_end_matches('/').to_string();
+    }
+
+    // handle_qr expects the path in form of: /qr/<url id>?format=svg&size=256&margin=4&ec=M, all
+    // the params being optional; without the format, the Accept header picks it
+    pub fn handle_qr(&mut self, stream: TcpStream, path: &str, host: &str, accept: &str) {
+        let (url_id, query) = match path.strip_prefix("/qr/") {
+            Some(qr_path) => qr_path.split_once('?').unwrap_or((qr_path, "")),
+            None => ("", ""),
+        };
+        if url_id.is_empty() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                String::from("url id param missing"),
+            );
+            return;
+        }
+        log_mdc::insert("link_id", url_id);
+
+        let options = match QrOptions::parse(query, accept) {
+            Ok(options) => options,
+            Err(err) => {
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+
+        // the id can be the link's own, or one of its aliases
+        let url_key = format!("short_url::{}", url_id);
+        match telemetry::traced("redis EXISTS", || {
+            self.redis_conn
+                .exists::<_, i32>(&[url_key.clone(), alias_key(url_id)])
+        }) {
+            Ok(n) if n > 0 => {}
+            Ok(_) => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("url [{}] not found", url_id),
+                );
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute EXISTS for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        }
+
+        // without a configured public url, the one the request came to is used
+        let base_url = match self.public_url.as_str() {
+            "" => format!("http://{}", host),
+            public_url => public_url.to_string(),
+        };
+        let short_url = format!("{}/l/{}", base_url, urlencoding::encode(url_id));
+        debug!(">>> generating qr code for: [{}]", short_url);
+
+        let (content_type, image) = match render_qr(&short_url, &options) {
+            Ok(rendered) => rendered,
+            Err(err) => {
+                debug!("failed to generate qr code for [{}]: {}", short_url, err);
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err,
+                );
+                return;
+            }
+        };
+        Handlers::binary_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            content_type,
+            &[("Cache-Control", "public, max-age=86400".to_string())],
+            &image,
+        );
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QrFormat {
+    Svg,
+    Png,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QrOptions {
+    pub format: QrFormat,
+    // minimum width and height of the image, in pixels
+    pub size: u32,
+    // quiet zone around the code, in modules
+    pub margin: u32,
+    pub ec_level: EcLevel,
+}
+
+impl QrOptions {
+    // parse reads the options from the query params, the format falls back to the one asked for
+    // in the Accept header, and to svg
+    pub fn parse(query: &str, accept: &str) -> Result<QrOptions, String> {
+        let mut options = QrOptions {
+            format: if accept.contains("image/png") && !accept.contains("image/svg+xml") {
+                QrFormat::Png
+            } else {
+                QrFormat::Svg
+            },
+            size: DEFAULT_SIZE,
+            margin: DEFAULT_MARGIN,
+            ec_level: EcLevel::M,
+        };
+        for (name, value) in form_urlencoded::parse(query.as_bytes()) {
+            match name.as_ref() {
+                "format" => {
+                    options.format = match value.to_ascii_lowercase().as_str() {
+                        "svg" => QrFormat::Svg,
+                        "png" => QrFormat::Png,
+                        _ => return Err(format!("invalid format {}, expected svg or png", value)),
+                    }
+                }
+                "size" => {
+                    options.size = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|s| (MIN_SIZE..=MAX_SIZE).contains(s))
+                        .ok_or(format!(
+                            "invalid size {}, expected {} to {}",
+                            value, MIN_SIZE, MAX_SIZE
+                        ))?
+                }
+                "margin" => {
+                    options.margin = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|m| *m <= MAX_MARGIN)
+                        .ok_or(format!(
+                            "invalid margin {}, expected 0 to {}",
+                            value, MAX_MARGIN
+                        ))?
+                }
+                "ec" => {
+                    options.ec_level = match value.to_ascii_uppercase().as_str() {
+                        "L" => EcLevel::L,
+                        "M" => EcLevel::M,
+                        "Q" => EcLevel::Q,
+                        "H" => EcLevel::H,
+                        _ => return Err(format!("invalid ec {}, expected L, M, Q or H", value)),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(options)
+    }
+}
+
+// render_qr returns the content type and the image of the qr code encoding the text
+pub fn render_qr(text: &str, options: &QrOptions) -> Result<(&'static str, Vec<u8>), String> {
+    let code = QrCode::with_error_correction_level(text, options.ec_level)
+        .map_err(|e| format!("failed to encode qr code: {}", e))?;
+    let width = code.width();
+    let modules: Vec<bool> = code
+        .to_colors()
+        .into_iter()
+        .map(|c| c == Color::Dark)
+        .collect();
+    match options.format {
+        QrFormat::Svg => Ok((
+            "image/svg+xml",
+            render_svg(&modules, width, options).into_bytes(),
+        )),
+        QrFormat::Png => Ok(("image/png", render_png(&modules, width, options)?)),
+    }
+}
+
+// render_svg draws the dark modules as unit squares of a single path, scaled to the size
+fn render_svg(modules: &[bool], width: usize, options: &QrOptions) -> String {
+    let margin = options.margin as usize;
+    let total = width + 2 * margin;
+    let mut path = String::new();
+    for (i, _) in modules.iter().enumerate().filter(|(_, dark)| **dark) {
+        let _ = write!(
+            path,
+            "M{} {}h1v1h-1z",
+            i % width + margin,
+            i / width + margin
+        );
+    }
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\
+         <svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{size}\" height=\"{size}\" \
+         viewBox=\"0 0 {total} {total}\" shape-rendering=\"crispEdges\">\
+         <rect width=\"{total}\" height=\"{total}\" fill=\"#fff\"/>\
+         <path fill=\"#000\" d=\"{path}\"/></svg>",
+        size = options.size,
+    )
+}
+
+// render_png draws each module as a square of whole pixels, so the image is at least the size
+fn render_png(modules: &[bool], width: usize, options: &QrOptions) -> Result<Vec<u8>, String> {
+    let margin = options.margin as usize;
+    let total = width + 2 * margin;
+    let module_px = (options.size as usize).div_ceil(total);
+    let image_px = total * module_px;
+
+    let mut pixels = vec![255u8; image_px * image_px];
+    for (i, _) in modules.iter().enumerate().filter(|(_, dark)| **dark) {
+        let (x, y) = (
+            (i % width + margin) * module_px,
+            (i / width + margin) * module_px,
+        );
+        for row in y..y + module_px {
+            pixels[row * image_px + x..row * image_px + x + module_px].fill(0);
+        }
+    }
+
+    let mut image = vec![];
+    let mut encoder = png::Encoder::new(&mut image, image_px as u32, image_px as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(|e| e.to_string())?;
+    writer
+        .write_image_data(&pixels)
+        .map_err(|e| e.to_string())?;
+    writer.finish().map_err(|e| e.to_string())?;
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render_qr, QrFormat, QrOptions};
+    use qrcode::EcLevel;
+
+    #[test]
+    fn test_parse_options() {
+        assert_eq!(
+            QrOptions::parse("", ""),
+            Ok(QrOptions {
+                format: QrFormat::Svg,
+                size: 256,
+                margin: 4,
+                ec_level: EcLevel::M,
+            })
+        );
+        assert_eq!(
+            QrOptions::parse("size=512&margin=0&ec=h", "image/png,*/*"),
+            Ok(QrOptions {
+                format: QrFormat::Png,
+                size: 512,
+                margin: 0,
+                ec_level: EcLevel::H,
+            })
+        );
+        // the query param wins over the Accept header
+        assert_eq!(
+            QrOptions::parse("format=svg", "image/png").map(|o| o.format),
+            Ok(QrFormat::Svg)
+        );
+        for query in [
+            "format=gif",
+            "size=16",
+            "size=4096",
+            "size=big",
+            "margin=21",
+            "ec=X",
+        ] {
+            assert!(QrOptions::parse(query, "").is_err(), "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_render_qr() {
+        let mut options = QrOptions::parse("size=100&margin=2", "").unwrap();
+        let (content_type, svg) = render_qr("https://x.xyz/l/abc", &options).unwrap();
+        let svg = String::from_utf8(svg).unwrap();
+        assert_eq!(content_type, "image/svg+xml");
+        // version 2 (25 modules) with the margin
+        assert!(svg.contains("width=\"100\" height=\"100\" viewBox=\"0 0 29 29\""));
+        // the top left finder pattern starts right after the margin
+        assert!(svg.contains("d=\"M2 2h1v1h-1z"));
+
+        options.format = QrFormat::Png;
+        let (content_type, png) = render_qr("https://x.xyz/l/abc", &options).unwrap();
+        assert_eq!(content_type, "image/png");
+        let decoder = png::Decoder::new(png.as_slice());
+        let reader = decoder.read_info().unwrap();
+        // 4px modules, the smallest whole size covering 100px
+        assert_eq!((reader.info().width, reader.info().height), (116, 116));
+    }
+}
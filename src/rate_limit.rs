@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// how many clients are tracked before the ones with expired windows are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+// RateLimiter allows each client ip (or other key, e.g. a link id) a number of requests within
+// a fixed window
+pub struct RateLimiter<K = IpAddr> {
+    limit: u32,
+    window: Duration,
+    clients: HashMap<K, (Instant, u32)>,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    pub fn new(limit: u32, window: Duration) -> RateLimiter<K> {
+        RateLimiter {
+            limit,
+            window,
+            clients: HashMap::new(),
+        }
+    }
+
+    // set_limit changes the limit, the counts of the ongoing windows are kept
+    pub fn set_limit(&mut self, limit: u32) {
+        self.limit = limit;
+        if limit == 0 {
+            self.clients.clear();
+        }
+    }
+
+    // check counts the request, and if it's over the limit tells how long the client has to
+    // wait; a limit of 0 lets everything through
+    pub fn check(&mut self, client_ip: K, now: Instant) -> Result<(), Duration> {
+        if self.limit == 0 {
+            return Ok(());
+        }
+
+        if self.clients.len() >= MAX_TRACKED_CLIENTS {
+            let window = self.window;
+            self.clients
+                .retain(|_, (started_at, _)| now.duration_since(*started_at) < window);
+        }
+
+        let (started_at, count) = self.clients.entry(client_ip).or_insert((now, 0));
+        if now.duration_since(*started_at) >= self.window {
+            *started_at = now;
+            *count = 0;
+        }
+        if *count >= self.limit {
+            return Err(self.window - now.duration_since(*started_at));
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    // retry_after tells how long the client has to wait, if it's over the limit, without
+    // counting a request
+    pub fn retry_after(&self, client_ip: &K, now: Instant) -> Option<Duration> {
+        if self.limit == 0 {
+            return None;
+        }
+        let (started_at, count) = self.clients.get(client_ip)?;
+        let elapsed = now.duration_since(*started_at);
+        if elapsed >= self.window || *count < self.limit {
+            return None;
+        }
+        Some(self.window - elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_check() {
+        let mut limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+        let now = Instant::now();
+
+        assert_eq!(limiter.check(ip, now), Ok(()));
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(10)), Ok(()));
+        assert_eq!(
+            limiter.check(ip, now + Duration::from_secs(20)),
+            Err(Duration::from_secs(40))
+        );
+        assert_eq!(
+            limiter.check(other_ip, now + Duration::from_secs(20)),
+            Ok(())
+        );
+        // a new window starts
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(60)), Ok(()));
+
+        assert_eq!(
+            limiter.retry_after(&ip, now + Duration::from_secs(70)),
+            None
+        );
+        assert_eq!(limiter.check(ip, now + Duration::from_secs(70)), Ok(()));
+        assert_eq!(
+            limiter.retry_after(&ip, now + Duration::from_secs(80)),
+            Some(Duration::from_secs(40))
+        );
+        assert_eq!(limiter.retry_after(&other_ip, now), None);
+
+        limiter.set_limit(0);
+        for _ in 0..10 {
+            assert_eq!(limiter.check(ip, now + Duration::from_secs(61)), Ok(()));
+        }
+    }
+}
@@ -1,27 +1,98 @@
+use chrono::{DateTime, Local};
 use http::StatusCode;
 use redis::RedisError;
 
+use crate::access_log::{self, AccessLogEntry};
+use crate::alias_handler::AliasHandler;
+use crate::analytics::AnalyticsConfig;
 use crate::auth_service::AuthService;
+use crate::client_ip::TrustedProxies;
+use crate::config::{HttpSettings, LinksSettings};
 use crate::delete_handler::DeleteHandler;
 use crate::get_all_handler::GetAllHandler;
-use crate::handlers::Handlers;
-use crate::link_handler::LinkHandler;
+use crate::handlers::{Handlers, ResponseInfo};
+use crate::health_handler::{HealthHandler, STORAGE_LATENCY_BUDGET};
+use crate::history::get_actor;
+use crate::history_handler::HistoryHandler;
+use crate::link_handler::{LinkHandler, LinkRequest, PasswordCheck, VerifiedPassword};
+use crate::metrics::{self, Metrics};
 use crate::new_handler::NewHandler;
-use log::{debug, error};
+use crate::privacy::IpAnonymization;
+use crate::qr_handler::QrHandler;
+use crate::rate_limit::RateLimiter;
+use crate::stats_handler::StatsHandler;
+use crate::telemetry::Tracer;
+use crate::thread_pool::PoolStats;
+use crate::trash_handler::TrashHandler;
+use crate::update_handler::UpdateHandler;
+use log::{debug, error, info};
+use rand::{thread_rng, Rng};
+use std::fs;
 use std::io::Read;
-use std::net::TcpStream;
+use std::net::{IpAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const MAX_REQUEST_ID_LEN: usize = 128;
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+// IncomingRequest is a request read from its stream, before it is routed
+pub struct IncomingRequest {
+    req_str: String,
+    started_at: Instant,
+    received_at: DateTime<Local>,
+}
+
+// read_request reads the request from the stream, which needs nothing of the router, so it's
+// done before the router is locked
+pub fn read_request(stream: &mut TcpStream) -> Option<IncomingRequest> {
+    let started_at = Instant::now();
+    let received_at = Local::now();
+    let mut buf = [0u8; 4096];
+    let n = match stream.read(&mut buf) {
+        Ok(n) => n,
+        Err(e) => {
+            error!("Unable to read stream: {}", e);
+            return None;
+        }
+    };
+    Some(IncomingRequest {
+        req_str: String::from_utf8_lossy(&buf[..n]).to_string(),
+        started_at,
+        received_at,
+    })
+}
 
 pub struct Router {
     suppress_logs: bool,
     is_verbose: bool,
+    trusted_proxies: TrustedProxies,
+    // client ips are logged as they'd be stored with a click, never raw
+    ip_anonymization: IpAnonymization,
+    // origins browsers may call the api from, "*" allows any
+    cors_origins: Vec<String>,
+    rate_limiter: RateLimiter,
 
     auth_service: AuthService,
+    metrics: Arc<Metrics>,
+    tracer: Tracer,
+    pool_stats: Option<Arc<PoolStats>>,
+    // set once a shutdown starts, so load balancers can route traffic away in time
+    draining: Arc<AtomicBool>,
 
     // handlers
     link_handler: LinkHandler,
     new_handler: NewHandler,
+    update_handler: UpdateHandler,
+    alias_handler: AliasHandler,
+    history_handler: HistoryHandler,
+    trash_handler: TrashHandler,
+    qr_handler: QrHandler,
     get_all_handler: GetAllHandler,
     delete_handler: DeleteHandler,
+    stats_handler: StatsHandler,
+    health_handler: HealthHandler,
 }
 
 impl Router {
@@ -30,24 +101,57 @@ impl Router {
         suppress_logs: bool,
         is_verbose: bool,
         with_insecure_auth_service: bool,
+        trusted_proxies: TrustedProxies,
+        analytics_config: AnalyticsConfig,
+        tracer: Tracer,
     ) -> Result<Router, RedisError> {
         let redis_client = redis::Client::open(String::from(&redis_conn_string))?;
         let redis_conn = redis_client.get_connection()?;
         let auth_service = AuthService::new(redis_conn, with_insecure_auth_service);
         // TODO: try to inject redis connection in other objects
 
-        let link_handler = LinkHandler::new(&redis_conn_string)?;
-        let new_handler = NewHandler::new(&redis_conn_string)?;
-        let delete_handler = DeleteHandler::new(&redis_conn_string)?;
-        let get_all_handler = crate::get_all_handler::GetAllHandler::new(&redis_conn_string)?;
+        let metrics = Arc::new(Metrics::new());
+        let ip_anonymization = analytics_config.ip_anonymization.clone();
+        let link_handler = LinkHandler::new(
+            &redis_conn_string,
+            analytics_config.geoip,
+            analytics_config.ip_anonymization,
+            Arc::clone(&metrics),
+        )?;
+        let new_handler = NewHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let update_handler = UpdateHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let alias_handler = AliasHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let history_handler = HistoryHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let trash_handler = TrashHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let qr_handler = QrHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let delete_handler = DeleteHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let get_all_handler =
+            crate::get_all_handler::GetAllHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let stats_handler = StatsHandler::new(&redis_conn_string, Arc::clone(&metrics))?;
+        let health_handler = HealthHandler::new(&redis_conn_string, STORAGE_LATENCY_BUDGET);
         Ok(Router {
             suppress_logs,
             is_verbose,
+            trusted_proxies,
+            ip_anonymization,
+            cors_origins: vec!["*".to_string()],
+            rate_limiter: RateLimiter::new(0, RATE_LIMIT_WINDOW),
             auth_service,
+            metrics,
+            tracer,
+            pool_stats: None,
+            draining: Arc::new(AtomicBool::new(false)),
             link_handler,
             new_handler,
+            update_handler,
+            alias_handler,
+            history_handler,
+            trash_handler,
+            qr_handler,
             get_all_handler,
             delete_handler,
+            stats_handler,
+            health_handler,
         })
     }
 
@@ -61,6 +165,49 @@ impl Router {
         self
     }
 
+    // set_link_cookie_secret sets the key signing the cookies of password protected links
+    pub fn set_link_cookie_secret(&mut self, secret: &str) {
+        self.link_handler.set_cookie_secret(secret);
+    }
+
+    // set_public_url sets where the service is reached from outside, for the short urls in qr
+    // codes
+    pub fn set_public_url(&mut self, public_url: &str) {
+        self.qr_handler.set_public_url(public_url);
+    }
+
+    // set_pool_stats makes the thread pool load visible in the metrics
+    pub fn set_pool_stats(&mut self, pool_stats: Arc<PoolStats>) {
+        self.pool_stats = Some(pool_stats);
+    }
+
+    // apply_settings swaps in the settings which can be changed while running; the router is
+    // locked for each request, so a request is served either with the old or the new ones
+    pub fn apply_settings(&mut self, links: &LinksSettings, http: &HttpSettings) {
+        self.link_handler.set_redirect_status(links.redirect_status);
+        self.link_handler
+            .set_cache_control(&links.redirect_cache_control);
+        self.link_handler.set_sticky_variants(links.sticky_variants);
+        self.link_handler
+            .set_not_started_page(read_page("links.not_started_page", &links.not_started_page));
+        self.link_handler
+            .set_preview_template(read_page("links.preview_template", &links.preview_template));
+        self.new_handler
+            .set_link_rules(&links.blocked_domains, &links.reserved_ids);
+        self.update_handler
+            .set_blocked_domains(&links.blocked_domains);
+        self.alias_handler.set_reserved_ids(&links.reserved_ids);
+        self.history_handler
+            .set_blocked_domains(&links.blocked_domains);
+        self.cors_origins = http.cors_origins.clone();
+        self.rate_limiter.set_limit(http.rate_limit_per_minute);
+    }
+
+    // draining returns the flag which marks the instance as not ready, once shutting down
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.draining)
+    }
+
     fn log(&self, message: String) {
         if self.suppress_logs {
             return;
@@ -68,70 +215,289 @@ impl Router {
         debug!("{}", message);
     }
 
-    pub fn route(&mut self, mut stream: TcpStream) {
-        let mut buf = [0u8; 4096];
-        match stream.read(&mut buf) {
-            Ok(_) => {
-                let req_str = String::from_utf8_lossy(&buf);
-                let req_str = req_str.trim_end();
-                if self.is_verbose {
-                    self.log(String::from("+++++++++++++++++++++++++++++++++"));
-                    self.log(String::from(format!(
-                        "incoming request, len [{}]:",
-                        req_str.len()
-                    )));
-                    self.log(format!("[[{}]]", req_str.to_string()));
-                    self.log(String::from("---------------------------------"));
-                } else {
-                    self.log(req_str.to_string());
-                }
+    // password_check returns the password posted to a protected link with the request, to be
+    // verified while the router isn't locked
+    pub fn password_check(
+        &mut self,
+        stream: &TcpStream,
+        request: &IncomingRequest,
+    ) -> Option<PasswordCheck> {
+        let req_str = request.req_str.trim_end();
+        let mut iter = req_str.split_whitespace().take(2);
+        let (method, path) = (iter.next()?, iter.next()?);
+        if method != "POST" || !path.starts_with("/l/") {
+            return None;
+        }
+        let client_ip = self.client_ip(stream, req_str);
+        if let Some(client_ip) = client_ip {
+            if self
+                .rate_limiter
+                .retry_after(&client_ip, Instant::now())
+                .is_some()
+            {
+                return None;
+            }
+        }
+        let post_body = get_req_body(req_str).unwrap_or_default();
+        self.link_handler.password_check(
+            path,
+            &LinkRequest {
+                client_ip,
+                record_hit: true,
+                cookie: &get_req_header("Cookie", req_str),
+                user_agent: "",
+                accept_language: "",
+                post_body: Some(&post_body),
+                verified_password: None,
+            },
+        )
+    }
 
-                if req_str == "" {
-                    self.log(String::from("received an empty request"));
-                    Handlers::handle_unknown_path(stream);
-                    return;
-                }
+    // route serves the request, with the posted password already verified if it was one
+    pub fn route(
+        &mut self,
+        stream: TcpStream,
+        request: &IncomingRequest,
+        verified_password: Option<&VerifiedPassword>,
+    ) {
+        // the worker thread is reused, so nothing of the previous request may leak in its logs
+        log_mdc::clear();
+        Handlers::set_request_id(None);
+        Handlers::set_allowed_origin(None);
+        Handlers::set_head_request(false);
+        Handlers::take_last_response();
+
+        self.serve(stream, request, verified_password);
+
+        log_mdc::clear();
+        Handlers::set_request_id(None);
+        Handlers::set_allowed_origin(None);
+        Handlers::set_head_request(false);
+    }
 
-                let mut iter = req_str.split_whitespace().take(2);
-                let method = match iter.next() {
-                    Some(m) => m,
-                    None => {
-                        Handlers::respond_with_status_code(
-                            stream,
-                            StatusCode::BAD_REQUEST.as_u16(),
-                            "http method not found".to_string(),
-                        );
-                        return;
-                    }
-                };
-                let path = match iter.next() {
-                    Some(p) => p,
-                    None => {
-                        Handlers::respond_with_status_code(
-                            stream,
-                            StatusCode::BAD_REQUEST.as_u16(),
-                            "http path not found".to_string(),
-                        );
-                        return;
-                    }
-                };
+    fn serve(
+        &mut self,
+        stream: TcpStream,
+        request: &IncomingRequest,
+        verified_password: Option<&VerifiedPassword>,
+    ) {
+        let (started_at, received_at) = (request.started_at, request.received_at);
+        let req_str = request.req_str.trim_end();
+
+        let request_id = get_request_id(&get_req_header("X-Request-Id", req_str));
+        log_mdc::insert("request_id", &request_id);
+        Handlers::set_request_id(Some(request_id));
+        Handlers::set_allowed_origin(get_allowed_origin(
+            &self.cors_origins,
+            &get_req_header("Origin", req_str),
+        ));
+
+        if self.is_verbose {
+            self.log(String::from("+++++++++++++++++++++++++++++++++"));
+            self.log(format!("incoming request, len [{}]:", req_str.len()));
+            self.log(format!("[[{}]]", req_str));
+            self.log(String::from("---------------------------------"));
+        } else {
+            self.log(req_str.to_string());
+        }
+
+        let client_ip = self.client_ip(&stream, req_str);
+
+        self.handle_request(stream, req_str, client_ip, started_at, verified_password);
+
+        if let Some(response) = Handlers::last_response() {
+            access_log::log(&AccessLogEntry {
+                client_ip,
+                received_at,
+                request_line: req_str.lines().next().unwrap_or_default(),
+                status: response.status,
+                bytes: response.bytes,
+                referer: &get_req_header("Referer", req_str),
+                user_agent: &get_req_header("User-Agent", req_str),
+            });
+        }
+    }
 
-                self.log(format!("==> serving [{}]: {}", method, path));
-                self.route_path(stream, method, path, &req_str);
+    fn client_ip(&self, stream: &TcpStream, req_str: &str) -> Option<IpAddr> {
+        let peer_ip = stream.peer_addr().ok().map(|addr| addr.ip());
+        self.trusted_proxies.client_ip(
+            peer_ip,
+            &get_req_header("Forwarded", req_str),
+            &get_req_header("X-Forwarded-For", req_str),
+            &get_req_header("X-Real-IP", req_str),
+        )
+    }
+
+    fn handle_request(
+        &mut self,
+        stream: TcpStream,
+        req_str: &str,
+        client_ip: Option<IpAddr>,
+        started_at: Instant,
+        verified_password: Option<&VerifiedPassword>,
+    ) {
+        if req_str.is_empty() {
+            self.log(String::from("received an empty request"));
+            Handlers::handle_unknown_path(stream);
+            return;
+        }
+
+        let mut iter = req_str.split_whitespace().take(2);
+        let method = match iter.next() {
+            Some(m) => m,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    "http method not found".to_string(),
+                );
+                return;
+            }
+        };
+        let path = match iter.next() {
+            Some(p) => p,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    "http path not found".to_string(),
+                );
+                return;
             }
-            Err(e) => error!("Unable to read stream: {}", e),
+        };
+
+        log_mdc::insert("method", method);
+        Handlers::set_head_request(method == "HEAD");
+        log_mdc::insert("path", path);
+
+        self.log(format!(
+            "==> serving [{}]: {} for {:?}",
+            method,
+            path,
+            client_ip.map(|ip| self.ip_anonymization.anonymize(ip))
+        ));
+        let route = metrics::route_label(path);
+        let mut span = self.tracer.start_request_span(
+            format!("{} {}", method, route),
+            &get_req_header("traceparent", req_str),
+        );
+        span.set_attribute("http.method", method.to_string());
+        span.set_attribute("http.target", path.to_string());
+        span.set_attribute("http.route", route.to_string());
+
+        match self.check_rate_limit(route, client_ip) {
+            Ok(_) => self.route_path(stream, method, path, req_str, client_ip, verified_password),
+            Err(retry_after) => {
+                debug!(
+                    "rate limit reached for {:?}",
+                    client_ip.map(|ip| self.ip_anonymization.anonymize(ip))
+                );
+                Handlers::handle_too_many_requests(stream, retry_after.as_secs().max(1));
+            }
+        }
+
+        let response = Handlers::last_response();
+        if let Some(response) = response {
+            span.set_attribute("http.status_code", response.status.to_string());
+            if response.status >= 500 {
+                span.set_error(format!("responded with {}", response.status));
+            }
+        }
+        self.observe_request(method, route, response, started_at);
+
+        if let Some(response) = response {
+            log_mdc::insert("status", response.status.to_string());
+        }
+        log_mdc::insert(
+            "latency_ms",
+            format!("{:.3}", started_at.elapsed().as_secs_f64() * 1000.0),
+        );
+        if !self.suppress_logs {
+            info!("<== served [{}]: {}", method, path);
         }
     }
 
-    fn route_path(&mut self, stream: TcpStream, method: &str, path: &str, req_str: &str) {
+    // check_rate_limit counts the request against the client's limit; probes and metrics
+    // scrapes are never limited
+    fn check_rate_limit(&mut self, route: &str, client_ip: Option<IpAddr>) -> Result<(), Duration> {
+        if matches!(route, "/healthz" | "/readyz" | "/metrics") {
+            return Ok(());
+        }
+        match client_ip {
+            Some(client_ip) => self.rate_limiter.check(client_ip, Instant::now()),
+            None => Ok(()),
+        }
+    }
+
+    fn observe_request(
+        &self,
+        method: &str,
+        route: &str,
+        response: Option<ResponseInfo>,
+        started_at: Instant,
+    ) {
+        let status = match response {
+            Some(r) => r.status,
+            None => return,
+        };
+        self.metrics
+            .observe_request(route, status, started_at.elapsed());
+
+        if route == "/l/{id}" && method != "HEAD" && (300..400).contains(&status) {
+            self.metrics.inc_redirects();
+        } else if route == "/new" && status == StatusCode::OK.as_u16() {
+            self.metrics.inc_links_created();
+        }
+    }
+
+    fn route_path(
+        &mut self,
+        stream: TcpStream,
+        method: &str,
+        path: &str,
+        req_str: &str,
+        client_ip: Option<IpAddr>,
+        verified_password: Option<&VerifiedPassword>,
+    ) {
         // get link and redirect to it
         if path.starts_with("/l/") {
-            if method != "GET" {
+            // the password of a protected link is posted to it
+            if method != "GET" && method != "HEAD" && method != "POST" {
                 Handlers::handle_method_not_allowed(stream, method);
                 return;
             }
 
-            self.link_handler.handle_link(stream, path);
+            // link checkers only look, so HEAD isn't counted as a hit
+            let record_hit = method != "HEAD";
+            let post_body = match method {
+                "POST" => Some(get_req_body(req_str).unwrap_or_default()),
+                _ => None,
+            };
+            self.link_handler.handle_link(
+                stream,
+                path,
+                &LinkRequest {
+                    client_ip,
+                    record_hit,
+                    cookie: &get_req_header("Cookie", req_str),
+                    user_agent: &get_req_header("User-Agent", req_str),
+                    accept_language: &get_req_header("Accept-Language", req_str),
+                    post_body: post_body.as_deref(),
+                    verified_password,
+                },
+            );
+            return;
+        } else if path.starts_with("/qr/") {
+            if method != "GET" && method != "HEAD" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+            self.qr_handler.handle_qr(
+                stream,
+                path,
+                &get_req_header("Host", req_str),
+                &get_req_header("Accept", req_str),
+            );
             return;
         } else if path.starts_with("/delete") {
             if method == "OPTIONS" {
@@ -140,10 +506,7 @@ impl Router {
             } else if method != "DELETE" {
                 let session_token = get_req_header("X-SERJ-TOKEN", req_str);
                 if !self.auth_service.is_logged(&session_token) {
-                    debug!(
-                        "unauthorized access to /delete detected with [{}]",
-                        session_token
-                    );
+                    debug!("unauthorized access to /delete detected");
                     Handlers::handle_unauthorized(stream);
                     return;
                 }
@@ -152,12 +515,220 @@ impl Router {
                 return;
             }
 
-            self.delete_handler.handle_delete(stream, path);
+            let actor = get_actor(
+                &get_req_header("X-Actor", req_str),
+                &get_req_header("X-SERJ-TOKEN", req_str),
+            );
+            self.delete_handler.handle_delete(stream, path, &actor);
+            return;
+        } else if path.starts_with("/stats") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "GET");
+                return;
+            } else if method != "GET" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /stats detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            self.stats_handler.handle_stats(stream, path);
+            return;
+        } else if path.starts_with("/restore") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "POST");
+                return;
+            } else if method != "POST" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /restore detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            let actor = get_actor(&get_req_header("X-Actor", req_str), &session_token);
+            self.trash_handler.handle_restore(stream, path, &actor);
+            return;
+        } else if path.starts_with("/history") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "GET");
+                return;
+            } else if method != "GET" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /history detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            self.history_handler.handle_history(stream, path);
+            return;
+        } else if path.starts_with("/rollback") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "POST");
+                return;
+            } else if method != "POST" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /rollback detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            let post_body = match get_req_body(req_str) {
+                Some(body) => body,
+                None => {
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::BAD_REQUEST.as_u16(),
+                        String::from("missing request body"),
+                    );
+                    return;
+                }
+            };
+
+            let content_type = get_req_header("Content-Type", req_str);
+            let actor = get_actor(&get_req_header("X-Actor", req_str), &session_token);
+            self.history_handler
+                .handle_rollback(stream, path, post_body, content_type, &actor);
+            return;
+        } else if path.starts_with("/analytics") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "DELETE");
+                return;
+            } else if method != "DELETE" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /analytics detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            self.stats_handler.handle_wipe_analytics(stream, path);
+            return;
+        } else if path.starts_with("/update") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "PATCH");
+                return;
+            } else if method != "PATCH" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /update detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            let post_body = match get_req_body(req_str) {
+                Some(body) => body,
+                None => {
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::BAD_REQUEST.as_u16(),
+                        String::from("missing request body"),
+                    );
+                    return;
+                }
+            };
+
+            let content_type = get_req_header("Content-Type", req_str);
+            let actor = get_actor(&get_req_header("X-Actor", req_str), &session_token);
+            self.update_handler
+                .handle_update(stream, path, post_body, content_type, &actor);
+            return;
+        } else if path.starts_with("/alias") {
+            if method == "OPTIONS" {
+                Handlers::respond_options_ok(stream, path, "POST");
+                return;
+            } else if method != "POST" {
+                Handlers::handle_method_not_allowed(stream, method);
+                return;
+            }
+
+            let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+            if !self.auth_service.is_logged(&session_token) {
+                debug!("unauthorized access to /alias detected");
+                Handlers::handle_unauthorized(stream);
+                return;
+            }
+
+            let post_body = match get_req_body(req_str) {
+                Some(body) => body,
+                None => {
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::BAD_REQUEST.as_u16(),
+                        String::from("missing request body"),
+                    );
+                    return;
+                }
+            };
+
+            let content_type = get_req_header("Content-Type", req_str);
+            let actor = get_actor(&get_req_header("X-Actor", req_str), &session_token);
+            self.alias_handler
+                .handle_alias(stream, path, post_body, content_type, &actor);
             return;
         }
 
-        match path {
+        // query params are for the handlers to pick up
+        match path.split('?').next().unwrap_or(path) {
             "/ping" => Handlers::handle_ping(stream),
+            "/healthz" => {
+                if method == "GET" {
+                    self.health_handler.handle_healthz(stream);
+                } else {
+                    Handlers::handle_method_not_allowed(stream, method);
+                }
+            }
+            "/readyz" => {
+                if method == "GET" {
+                    self.health_handler.handle_readyz(
+                        stream,
+                        self.pool_stats.as_deref(),
+                        self.draining.load(Ordering::Relaxed),
+                    );
+                } else {
+                    Handlers::handle_method_not_allowed(stream, method);
+                }
+            }
+            "/metrics" => {
+                if method == "GET" {
+                    let metrics = self.metrics.render(self.pool_stats.as_deref());
+                    Handlers::text_response(
+                        stream,
+                        StatusCode::OK.as_u16(),
+                        "text/plain; version=0.0.4; charset=utf-8",
+                        metrics,
+                    );
+                } else {
+                    Handlers::handle_method_not_allowed(stream, method);
+                }
+            }
             "/hi" => {
                 if method == "GET" {
                     Handlers::handle_hello_world(stream);
@@ -176,29 +747,27 @@ impl Router {
 
                 let session_token = get_req_header("X-SERJ-TOKEN", req_str);
                 if !self.auth_service.is_logged(&session_token) {
-                    debug!(
-                        "unauthorized access to /new detected with [{}]",
-                        session_token
-                    );
+                    debug!("unauthorized access to /new detected");
                     Handlers::handle_unauthorized(stream);
                     return;
                 }
 
-                let post_body;
-                let mut iter = req_str.lines().rev().take(1);
-                if let Some(body) = iter.next() {
-                    post_body = String::from(body.trim_matches(char::from(0)));
-                } else {
-                    Handlers::respond_with_status_code(
-                        stream,
-                        StatusCode::BAD_REQUEST.as_u16(),
-                        String::from("missing request body"),
-                    );
-                    return;
-                }
+                let post_body = match get_req_body(req_str) {
+                    Some(body) => body,
+                    None => {
+                        Handlers::respond_with_status_code(
+                            stream,
+                            StatusCode::BAD_REQUEST.as_u16(),
+                            String::from("missing request body"),
+                        );
+                        return;
+                    }
+                };
 
                 let content_type = get_req_header("Content-Type", req_str);
-                self.new_handler.handle_new(stream, post_body, content_type);
+                let actor = get_actor(&get_req_header("X-Actor", req_str), &session_token);
+                self.new_handler
+                    .handle_new(stream, post_body, content_type, &actor);
             }
             "/all" => {
                 if method == "OPTIONS" {
@@ -206,15 +775,28 @@ impl Router {
                 } else if method == "GET" {
                     let session_token = get_req_header("X-SERJ-TOKEN", req_str);
                     if !self.auth_service.is_logged(&session_token) {
-                        debug!(
-                            "unauthorized access to /all detected with [{}]",
-                            session_token
-                        );
+                        debug!("unauthorized access to /all detected");
                         Handlers::handle_unauthorized(stream);
                         return;
                     }
 
-                    self.get_all_handler.handle_get_all(stream);
+                    self.get_all_handler.handle_get_all(stream, path);
+                } else {
+                    Handlers::handle_method_not_allowed(stream, method);
+                }
+            }
+            "/trash" => {
+                if method == "OPTIONS" {
+                    Handlers::respond_options_ok(stream, path, "GET");
+                } else if method == "GET" {
+                    let session_token = get_req_header("X-SERJ-TOKEN", req_str);
+                    if !self.auth_service.is_logged(&session_token) {
+                        debug!("unauthorized access to /trash detected");
+                        Handlers::handle_unauthorized(stream);
+                        return;
+                    }
+
+                    self.trash_handler.handle_trash(stream);
                 } else {
                     Handlers::handle_method_not_allowed(stream, method);
                 }
@@ -224,29 +806,74 @@ impl Router {
     }
 }
 
+// get_request_id takes the request id given by the client (or a proxy in front of us), if it's
+// safe to echo back, otherwise generates a new one
+fn get_request_id(header_value: &str) -> String {
+    let is_valid = !header_value.is_empty()
+        && header_value.len() <= MAX_REQUEST_ID_LEN
+        && header_value.bytes().all(|b| b.is_ascii_graphic());
+    if is_valid {
+        return header_value.to_string();
+    }
+    format!("{:032x}", thread_rng().gen::<u128>())
+}
+
+// get_allowed_origin returns the origin responses can be read from by browsers, given the Origin
+// header of the request
+fn get_allowed_origin(cors_origins: &[String], origin: &str) -> Option<String> {
+    if cors_origins.iter().any(|o| o == "*") {
+        return Some("*".to_string());
+    }
+    cors_origins
+        .iter()
+        .find(|o| !origin.is_empty() && o.eq_ignore_ascii_case(origin))
+        .map(|_| origin.to_string())
+}
+
+// read_page reads the html page configured by the setting, None when it's not set or can't be
+// read
+fn read_page(setting: &str, path: &Option<String>) -> Option<String> {
+    let path = path.as_ref()?;
+    match fs::read_to_string(path) {
+        Ok(page) => Some(page),
+        Err(e) => {
+            error!("failed to read {} [{}]: {}", setting, path, e);
+            None
+        }
+    }
+}
+
+// get_req_body returns the last line of the request, which is where the body is expected
+fn get_req_body(req_str: &str) -> Option<String> {
+    req_str
+        .lines()
+        .next_back()
+        .map(|body| String::from(body.trim_matches(char::from(0))))
+}
+
 fn get_req_header(header: &str, req_str: &str) -> String {
     for line in req_str.lines() {
         let mut next_line = line.trim_start();
         next_line = next_line.trim_end();
 
-        if !next_line.starts_with(header) {
-            continue;
-        }
-
-        let header_parts: Vec<&str> = next_line.split_terminator(":").collect();
-        if header_parts.len() != 2 {
+        // split on the first colon only, header values (urls, ipv6 addresses) can contain more
+        let (name, value) = match next_line.split_once(':') {
+            Some(parts) => parts,
+            None => continue,
+        };
+        if !name.eq_ignore_ascii_case(header) {
             continue;
         }
 
-        return header_parts[1].trim_start().trim_end().to_string();
+        return value.trim_start().trim_end().to_string();
     }
 
-    return "".to_string();
+    "".to_string()
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::router::get_req_header;
+    use crate::router::{get_allowed_origin, get_req_header, get_request_id};
 
     #[test]
     fn test_get_req_header() {
@@ -285,4 +912,37 @@ mod tests {
         let got_header_value = get_req_header("Content-Type", example_req);
         assert_eq!(got_header_value, "application/json");
     }
+
+    #[test]
+    fn test_get_request_id() {
+        assert_eq!(get_request_id("req-123"), "req-123");
+
+        let generated = get_request_id("");
+        assert_eq!(generated.len(), 32);
+        assert_ne!(generated, get_request_id(""));
+        assert_ne!(get_request_id("has spaces"), "has spaces");
+        assert_ne!(get_request_id(&"a".repeat(129)), "a".repeat(129));
+    }
+
+    #[test]
+    fn test_get_allowed_origin() {
+        let any = vec!["*".to_string()];
+        assert_eq!(
+            get_allowed_origin(&any, "https://a.com"),
+            Some("*".to_string())
+        );
+        assert_eq!(get_allowed_origin(&any, ""), Some("*".to_string()));
+
+        let listed = vec![
+            "https://app.example.com".to_string(),
+            "http://localhost:3000".to_string(),
+        ];
+        assert_eq!(
+            get_allowed_origin(&listed, "http://localhost:3000"),
+            Some("http://localhost:3000".to_string())
+        );
+        assert_eq!(get_allowed_origin(&listed, "https://evil.com"), None);
+        assert_eq!(get_allowed_origin(&listed, ""), None);
+        assert_eq!(get_allowed_origin(&[], "https://app.example.com"), None);
+    }
 }
@@ -1,48 +1,119 @@
-use crate::router::Router;
+use crate::analytics::AnalyticsConfig;
+use crate::config::Config;
+use crate::geoip::GeoIp;
+use crate::janitor::Janitor;
+use crate::link_handler::PasswordCheck;
+use crate::router::{self, Router};
+use crate::telemetry::Tracer;
 use crate::thread_pool::ThreadPool;
-use log::{debug, warn};
+use log::{debug, error, warn};
 use redis::RedisError;
 use std::net::TcpListener;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 
+// how often the janitor looks for expired data
+const JANITOR_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
 pub struct Server {
     address: String,
+    redis_conn_string: String,
     router: Arc<Mutex<Router>>,
     max_concurrent_requests: usize,
+    clicks_retention_days: i64,
+    trash_retention_days: i64,
 }
 
 impl Server {
     pub fn new(
-        redis_conn_string: String,
-        address: String,
-        max_concurrent_requests: usize,
-        with_insecure_auth_service: bool,
+        config: &Config,
+        geoip: Option<GeoIp>,
+        tracer: Tracer,
     ) -> Result<Server, RedisError> {
-        let router =
-            Router::new(redis_conn_string, false, true, with_insecure_auth_service)?.with_logs();
+        let redis_conn_string = config.redis_conn_string();
+        let mut router = Router::new(
+            redis_conn_string.clone(),
+            false,
+            true,
+            config.insecure,
+            config.trusted_proxies(),
+            AnalyticsConfig {
+                geoip,
+                ip_anonymization: config.ip_anonymization(),
+                clicks_retention_days: config.analytics.clicks_retention_days,
+            },
+            tracer,
+        )?
+        .with_logs();
+        router.set_link_cookie_secret(&config.link_cookie_secret);
+        router.set_public_url(&config.public_url);
+        router.apply_settings(&config.links, &config.http);
         let router = Arc::new(Mutex::new(router));
 
         Ok(Server {
-            address,
+            address: config.address(),
+            redis_conn_string,
             router,
-            max_concurrent_requests,
+            max_concurrent_requests: config.pool_size,
+            clicks_retention_days: config.analytics.clicks_retention_days,
+            trash_retention_days: config.trash_retention_days,
         })
     }
 
+    // draining returns the flag which, once set, makes the instance report itself as not ready
+    pub fn draining(&self) -> Arc<AtomicBool> {
+        self.router.lock().unwrap().draining()
+    }
+
+    // router returns the router handle, so settings can be swapped in while serving
+    pub fn router(&self) -> Arc<Mutex<Router>> {
+        Arc::clone(&self.router)
+    }
+
     pub fn start(&self) {
         let listener = TcpListener::bind(&self.address).unwrap();
         debug!("listening for connections ...");
 
+        // raw click events and deleted links are purged in the background after their
+        // retention periods
+        match Janitor::new(
+            &self.redis_conn_string,
+            chrono::Duration::days(self.clicks_retention_days),
+            chrono::Duration::days(self.trash_retention_days),
+            JANITOR_INTERVAL,
+        ) {
+            Ok(janitor) => {
+                janitor.start();
+            }
+            Err(e) => error!(
+                "failed to create janitor, expired data won't be purged: {}",
+                e
+            ),
+        }
+
         // control requests via Thread Pool
         let pool = ThreadPool::new(self.max_concurrent_requests);
+        self.router.lock().unwrap().set_pool_stats(pool.stats());
 
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
                     let router_clone = Arc::clone(&(self.router));
                     pool.execute(move || {
+                        let mut stream = stream;
+                        let request = match router::read_request(&mut stream) {
+                            Some(request) => request,
+                            None => return,
+                        };
+                        // passwords of protected links are verified without holding the router,
+                        // argon2 would stall all other requests
+                        let password_check = router_clone
+                            .lock()
+                            .unwrap()
+                            .password_check(&stream, &request);
+                        let verified_password = password_check.map(PasswordCheck::verify);
                         let mut r = router_clone.lock().unwrap();
-                        r.route(stream);
+                        r.route(stream, &request, verified_password.as_ref());
                     });
                 }
                 Err(e) => {
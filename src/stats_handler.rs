@@ -0,0 +1,232 @@
+use http::StatusCode;
+use log::debug;
+use redis::{Commands, Connection, RedisError};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+extern crate redis;
+use crate::{
+    alias_handler::respond_not_found, analytics, handlers::Handlers, metrics::Metrics, telemetry,
+    url_record::URLRecord,
+};
+
+#[derive(Serialize)]
+struct LinkStats {
+    id: String,
+    url: String,
+    hits: i32,
+    countries: BTreeMap<String, i64>,
+    // raw clicks still within the retention period
+    recorded_clicks: i64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    variants: Vec<VariantStats>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    aliases: Vec<AliasStats>,
+}
+
+#[derive(Serialize)]
+struct VariantStats {
+    url: String,
+    weight: u32,
+    hits: i64,
+}
+
+// AliasStats counts the clicks through one alias, they're part of the hits of the link too
+#[derive(Serialize)]
+struct AliasStats {
+    alias: String,
+    hits: i64,
+}
+
+pub struct StatsHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+}
+
+impl StatsHandler {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<StatsHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(StatsHandler {
+            redis_conn,
+            metrics,
+        })
+    }
+
+    // handle_stats expects the path in form of: /stats?id=<url id>
+    pub fn handle_stats(&mut self, stream: TcpStream, path: &str) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let url_key = format!("short_url::{}", id);
+        let url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                respond_not_found(&mut self.redis_conn, &self.metrics, stream, id);
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let countries = match analytics::get_countries(&mut self.redis_conn, id) {
+            Ok(c) => c,
+            Err(err) => {
+                debug!("failed to get countries for [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let recorded_clicks = match analytics::get_clicks_count(&mut self.redis_conn, id) {
+            Ok(c) => c,
+            Err(err) => {
+                debug!("failed to get clicks count for [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let variants = if url_record.variants.is_empty() {
+            vec![]
+        } else {
+            let variant_hits = match analytics::get_variant_hits(&mut self.redis_conn, id) {
+                Ok(h) => h,
+                Err(err) => {
+                    debug!("failed to get variant hits for [{}]: {}", id, err);
+                    self.metrics.inc_redis_errors();
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err.to_string(),
+                    );
+                    return;
+                }
+            };
+            url_record
+                .variants
+                .iter()
+                .map(|v| VariantStats {
+                    url: v.url.clone(),
+                    weight: v.weight,
+                    hits: variant_hits.get(&v.url).copied().unwrap_or(0),
+                })
+                .collect()
+        };
+
+        let aliases = if url_record.aliases.is_empty() {
+            vec![]
+        } else {
+            let alias_hits = match analytics::get_alias_hits(&mut self.redis_conn, id) {
+                Ok(h) => h,
+                Err(err) => {
+                    debug!("failed to get alias hits for [{}]: {}", id, err);
+                    self.metrics.inc_redis_errors();
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err.to_string(),
+                    );
+                    return;
+                }
+            };
+            url_record
+                .aliases
+                .iter()
+                .map(|a| AliasStats {
+                    alias: a.clone(),
+                    hits: alias_hits.get(a).copied().unwrap_or(0),
+                })
+                .collect()
+        };
+
+        let stats = LinkStats {
+            id: url_record.id,
+            url: url_record.url,
+            hits: url_record.hits,
+            countries,
+            recorded_clicks,
+            variants,
+            aliases,
+        };
+        let res_json = serde_json::to_string(&stats).unwrap();
+
+        Handlers::json_response(stream, StatusCode::OK.as_u16(), res_json);
+    }
+
+    // handle_wipe_analytics removes all collected analytics of a link, expects the path
+    // in form of: /analytics?id=<url id>
+    pub fn handle_wipe_analytics(&mut self, stream: TcpStream, path: &str) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        match analytics::wipe(&mut self.redis_conn, id) {
+            Ok(del_res) => {
+                let log_msg = format!("wipe analytics [{}] result: {}", id, del_res);
+                debug!(">>> {}", log_msg);
+                Handlers::respond_with_status_code(stream, StatusCode::OK.as_u16(), log_msg);
+            }
+            Err(err) => {
+                debug!("failed to wipe analytics for [{}]: {}", id, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+            }
+        }
+    }
+}
+
+pub fn get_id_param(path: &str) -> Option<&str> {
+    match path.split_once("?id=") {
+        Some((_, id)) if !id.is_empty() => Some(id),
+        _ => None,
+    }
+}
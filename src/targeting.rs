@@ -0,0 +1,146 @@
+use crate::url_record::Target;
+
+// choose_target returns the first target matching the client, as told by its User-Agent and
+// Accept-Language headers
+pub fn choose_target<'a>(
+    targets: &'a [Target],
+    user_agent: &str,
+    accept_language: &str,
+) -> Option<&'a Target> {
+    if targets.is_empty() {
+        return None;
+    }
+    let platform = get_platform(user_agent);
+    let language = get_preferred_language(accept_language);
+    targets
+        .iter()
+        .find(|target| target.matches(platform, language))
+}
+
+// get_platform tells the platform of the client from its User-Agent, None for the ones that
+// aren't browsers or apps of the known platforms (e.g. curl or bots)
+pub fn get_platform(user_agent: &str) -> Option<&'static str> {
+    // iPhone user agents mention "like Mac OS X", and Android ones mention Linux, so the
+    // mobile platforms are checked first
+    if ["iPhone", "iPad", "iPod"]
+        .iter()
+        .any(|device| user_agent.contains(device))
+    {
+        Some("ios")
+    } else if user_agent.contains("Android") {
+        Some("android")
+    } else if ["Windows", "Macintosh", "X11", "Linux", "CrOS"]
+        .iter()
+        .any(|os| user_agent.contains(os))
+    {
+        Some("desktop")
+    } else {
+        None
+    }
+}
+
+// get_preferred_language returns the language with the highest weight in Accept-Language,
+// the first one listed on a tie; e.g. "de" for "en;q=0.8, de"
+pub fn get_preferred_language(accept_language: &str) -> Option<&str> {
+    let mut preferred: Option<(&str, f32)> = None;
+    for item in accept_language.split(',') {
+        let mut parts = item.split(';');
+        let language = parts.next().unwrap_or_default().trim();
+        if language.is_empty() || language == "*" {
+            continue;
+        }
+        let weight = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .next()
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+        let weight = match weight {
+            Some(weight) if weight > 0.0 => weight,
+            _ => continue,
+        };
+        if preferred.is_none_or(|(_, w)| weight > w) {
+            preferred = Some((language, weight));
+        }
+    }
+    preferred.map(|(language, _)| language)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{choose_target, get_platform, get_preferred_language};
+    use crate::url_record::Target;
+
+    #[test]
+    fn test_get_platform() {
+        let cases = [
+            ("Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/17.0 Mobile/15E148 Safari/604.1", Some("ios")),
+            ("Mozilla/5.0 (iPad; CPU OS 16_6 like Mac OS X) AppleWebKit/605.1.15", Some("ios")),
+            ("Mozilla/5.0 (Linux; Android 14; Pixel 8) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0 Mobile Safari/537.36", Some("android")),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/118.0 Safari/537.36", Some("desktop")),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15", Some("desktop")),
+            ("Mozilla/5.0 (X11; Ubuntu; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/118.0", Some("desktop")),
+            ("curl/7.83.1", None),
+            ("", None),
+        ];
+        for (user_agent, want) in cases {
+            assert_eq!(get_platform(user_agent), want, "{}", user_agent);
+        }
+    }
+
+    #[test]
+    fn test_get_preferred_language() {
+        assert_eq!(
+            get_preferred_language("de-AT,de;q=0.9,en;q=0.8"),
+            Some("de-AT")
+        );
+        assert_eq!(get_preferred_language("en;q=0.8, de"), Some("de"));
+        assert_eq!(get_preferred_language("fr, en"), Some("fr"));
+        assert_eq!(get_preferred_language("*, es;q=0.5"), Some("es"));
+        assert_eq!(get_preferred_language("it;q=0, en;q=0.1"), Some("en"));
+        assert_eq!(get_preferred_language("en;q=abc"), None);
+        assert_eq!(get_preferred_language(""), None);
+    }
+
+    #[test]
+    fn test_choose_target() {
+        let targets = vec![
+            Target {
+                platform: Some("ios".to_string()),
+                language: None,
+                url: "https://apps.apple.com/app/id1".to_string(),
+            },
+            Target {
+                platform: Some("android".to_string()),
+                language: None,
+                url: "https://play.google.com/store/apps/details?id=xyz".to_string(),
+            },
+            Target {
+                platform: None,
+                language: Some("de".to_string()),
+                url: "https://2beens.xyz/de".to_string(),
+            },
+        ];
+        let target_url = |user_agent, accept_language| {
+            choose_target(&targets, user_agent, accept_language).map(|t| t.url.as_str())
+        };
+        assert_eq!(
+            target_url(
+                "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X)",
+                "de"
+            ),
+            Some("https://apps.apple.com/app/id1")
+        );
+        assert_eq!(
+            target_url("Mozilla/5.0 (Linux; Android 14; Pixel 8)", ""),
+            Some("https://play.google.com/store/apps/details?id=xyz")
+        );
+        assert_eq!(
+            target_url(
+                "Mozilla/5.0 (Windows NT 10.0; Win64; x64)",
+                "de-DE,en;q=0.5"
+            ),
+            Some("https://2beens.xyz/de")
+        );
+        assert_eq!(target_url("Mozilla/5.0 (Windows NT 10.0)", "en"), None);
+        assert_eq!(choose_target(&[], "curl/7.83.1", "de"), None);
+    }
+}
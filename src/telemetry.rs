@@ -0,0 +1,533 @@
+use log::{debug, error, info};
+use rand::{thread_rng, RngCore};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+const SERVICE_NAME: &str = "rust-url-shortener";
+const EXPORT_BATCH_SIZE: usize = 64;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(2);
+const EXPORT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// span kinds as defined by OTLP
+const SPAN_KIND_SERVER: u8 = 2;
+const SPAN_KIND_CLIENT: u8 = 3;
+
+// TraceContext is the W3C trace context, as propagated with the traceparent header:
+// traceparent: 00-<trace id, 32 hex>-<parent span id, 16 hex>-<flags, 2 hex>
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    pub fn parse_traceparent(traceparent: &str) -> Option<TraceContext> {
+        let parts: Vec<&str> = traceparent.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        // future versions may append fields, version 00 must have exactly 4
+        if parts[0] == "00" && parts.len() != 4 {
+            return None;
+        }
+
+        let trace_id: [u8; 16] = decode_hex(parts[1])?.try_into().ok()?;
+        let span_id: [u8; 8] = decode_hex(parts[2])?.try_into().ok()?;
+        let flags: [u8; 1] = decode_hex(parts[3])?.try_into().ok()?;
+        if trace_id == [0; 16] || span_id == [0; 8] {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 0x01 == 0x01,
+        })
+    }
+
+    pub fn to_traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            encode_hex(&self.trace_id),
+            encode_hex(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+// SpanData is a finished span, ready to be exported
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub kind: u8,
+    pub start_unix_nano: u128,
+    pub end_unix_nano: u128,
+    pub attributes: Vec<(String, String)>,
+    pub error: Option<String>,
+}
+
+// the trace of the request currently handled by this thread; storage calls made while
+// handling it become its child spans, without passing the trace around explicitly
+struct ActiveTrace {
+    trace_id: [u8; 16],
+    span_ids: Vec<[u8; 8]>,
+    exporter: Sender<SpanData>,
+}
+
+thread_local! {
+    static ACTIVE_TRACE: RefCell<Option<ActiveTrace>> = const { RefCell::new(None) };
+}
+
+// Tracer creates a span for each handled request; tracing is disabled unless an OTLP
+// exporter is configured
+pub struct Tracer {
+    exporter: Option<Sender<SpanData>>,
+}
+
+impl Tracer {
+    pub fn disabled() -> Tracer {
+        Tracer { exporter: None }
+    }
+
+    // with_otlp_exporter exports spans as OTLP/HTTP JSON to the given collector endpoint,
+    // e.g. http://localhost:4318 (spans are posted to /v1/traces if no path is given)
+    pub fn with_otlp_exporter(endpoint: &str) -> Result<Tracer, String> {
+        let exporter = OtlpExporter::new(endpoint)?;
+        info!("exporting traces to: {}", exporter.url);
+
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || exporter.run(receiver));
+        Ok(Tracer {
+            exporter: Some(sender),
+        })
+    }
+
+    // start_request_span starts the root span of a request, continuing the trace from the
+    // incoming traceparent header if there is a valid one
+    pub fn start_request_span(&self, name: String, traceparent: &str) -> RequestSpan {
+        let exporter = match &self.exporter {
+            Some(e) => e.clone(),
+            None => return RequestSpan { span: None },
+        };
+
+        let parent = TraceContext::parse_traceparent(traceparent);
+        if let Some(parent) = parent {
+            if !parent.sampled {
+                return RequestSpan { span: None };
+            }
+        }
+
+        let trace_id = match parent {
+            Some(p) => p.trace_id,
+            None => new_trace_id(),
+        };
+        let span = SpanData {
+            trace_id,
+            span_id: new_span_id(),
+            parent_span_id: parent.map(|p| p.span_id),
+            name,
+            kind: SPAN_KIND_SERVER,
+            start_unix_nano: now_unix_nano(),
+            end_unix_nano: 0,
+            attributes: vec![],
+            error: None,
+        };
+
+        ACTIVE_TRACE.with(|t| {
+            t.replace(Some(ActiveTrace {
+                trace_id,
+                span_ids: vec![span.span_id],
+                exporter,
+            }))
+        });
+
+        RequestSpan { span: Some(span) }
+    }
+}
+
+// RequestSpan is the root span of a request, it ends and gets exported when dropped
+pub struct RequestSpan {
+    span: Option<SpanData>,
+}
+
+impl RequestSpan {
+    pub fn set_attribute(&mut self, key: &str, value: String) {
+        if let Some(span) = &mut self.span {
+            span.attributes.push((key.to_string(), value));
+        }
+    }
+
+    pub fn set_error(&mut self, error: String) {
+        if let Some(span) = &mut self.span {
+            span.error = Some(error);
+        }
+    }
+}
+
+impl Drop for RequestSpan {
+    fn drop(&mut self) {
+        let mut span = match self.span.take() {
+            Some(s) => s,
+            None => return,
+        };
+        span.end_unix_nano = now_unix_nano();
+
+        if let Some(trace) = ACTIVE_TRACE.with(|t| t.take()) {
+            if let Err(e) = trace.exporter.send(span) {
+                debug!("failed to queue span for export: {}", e);
+            }
+        }
+    }
+}
+
+// traced runs the given storage operation in a child span of the current request
+// span, if there is one; errors returned by the operation are recorded on the span
+pub fn traced<T, E: Display>(name: &str, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let parent = ACTIVE_TRACE.with(|t| {
+        t.borrow_mut().as_mut().map(|trace| {
+            let span_id = new_span_id();
+            let parent_span_id = trace.span_ids.last().copied();
+            trace.span_ids.push(span_id);
+            (trace.trace_id, span_id, parent_span_id)
+        })
+    });
+    let (trace_id, span_id, parent_span_id) = match parent {
+        Some(p) => p,
+        None => return f(),
+    };
+
+    let start_unix_nano = now_unix_nano();
+    let res = f();
+    let end_unix_nano = now_unix_nano();
+
+    let operation = name.split_whitespace().last().unwrap_or(name);
+    let span = SpanData {
+        trace_id,
+        span_id,
+        parent_span_id,
+        name: name.to_string(),
+        kind: SPAN_KIND_CLIENT,
+        start_unix_nano,
+        end_unix_nano,
+        attributes: vec![
+            ("db.system".to_string(), "redis".to_string()),
+            ("db.operation".to_string(), operation.to_string()),
+        ],
+        error: res.as_ref().err().map(|e| e.to_string()),
+    };
+
+    ACTIVE_TRACE.with(|t| {
+        if let Some(trace) = t.borrow_mut().as_mut() {
+            trace.span_ids.pop();
+            if let Err(e) = trace.exporter.send(span) {
+                debug!("failed to queue span for export: {}", e);
+            }
+        }
+    });
+
+    res
+}
+
+struct OtlpExporter {
+    url: Url,
+}
+
+impl OtlpExporter {
+    fn new(endpoint: &str) -> Result<OtlpExporter, String> {
+        Ok(OtlpExporter {
+            url: parse_otlp_endpoint(endpoint)?,
+        })
+    }
+
+    // run exports spans in batches, until all tracers are gone
+    fn run(&self, receiver: Receiver<SpanData>) {
+        let mut batch = vec![];
+        let mut last_export = Instant::now();
+        loop {
+            let disconnected = match receiver.recv_timeout(EXPORT_INTERVAL) {
+                Ok(span) => {
+                    batch.push(span);
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+                Err(RecvTimeoutError::Disconnected) => true,
+            };
+
+            let batch_ready = batch.len() >= EXPORT_BATCH_SIZE
+                || last_export.elapsed() >= EXPORT_INTERVAL
+                || disconnected;
+            if batch_ready && !batch.is_empty() {
+                if let Err(e) = self.export(&batch) {
+                    error!("failed to export {} spans: {}", batch.len(), e);
+                }
+                batch.clear();
+                last_export = Instant::now();
+            }
+
+            if disconnected {
+                return;
+            }
+        }
+    }
+
+    fn export(&self, spans: &[SpanData]) -> Result<(), String> {
+        let body = to_otlp_json(spans).to_string();
+        let host = self.url.host_str().unwrap_or_default();
+        let port = self.url.port_or_known_default().unwrap_or(4318);
+
+        let addr = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| e.to_string())?
+            .next()
+            .ok_or(format!("failed to resolve {}", host))?;
+        let mut stream =
+            TcpStream::connect_timeout(&addr, EXPORT_TIMEOUT).map_err(|e| e.to_string())?;
+        stream
+            .set_read_timeout(Some(EXPORT_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        stream
+            .set_write_timeout(Some(EXPORT_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+
+        let request = format!(
+            "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.url.path(),
+            host,
+            port,
+            body.len(),
+            body
+        );
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+
+        let mut buf = [0u8; 512];
+        let n = stream.read(&mut buf).map_err(|e| e.to_string())?;
+        let response = String::from_utf8_lossy(&buf[..n]);
+        let status_line = response.lines().next().unwrap_or_default();
+        if !status_line
+            .split_whitespace()
+            .nth(1)
+            .unwrap_or_default()
+            .starts_with('2')
+        {
+            return Err(format!("collector responded with: {}", status_line));
+        }
+
+        debug!("exported {} spans", spans.len());
+        Ok(())
+    }
+}
+
+// parse_otlp_endpoint returns the url spans are posted to, only plain http is supported
+pub fn parse_otlp_endpoint(endpoint: &str) -> Result<Url, String> {
+    let mut url = Url::parse(endpoint).map_err(|e| format!("invalid endpoint: {}", e))?;
+    if url.scheme() != "http" {
+        return Err(format!("unsupported endpoint scheme: {}", url.scheme()));
+    }
+    if url.host_str().is_none() {
+        return Err("endpoint host missing".to_string());
+    }
+    if url.path() == "/" || url.path().is_empty() {
+        url.set_path("/v1/traces");
+    }
+    Ok(url)
+}
+
+fn to_otlp_json(spans: &[SpanData]) -> Value {
+    let spans: Vec<Value> = spans
+        .iter()
+        .map(|s| {
+            let attributes: Vec<Value> = s
+                .attributes
+                .iter()
+                .map(|(k, v)| json!({"key": k, "value": {"stringValue": v}}))
+                .collect();
+            let status = match &s.error {
+                Some(e) => json!({"code": 2, "message": e}),
+                None => json!({"code": 0}),
+            };
+            json!({
+                "traceId": encode_hex(&s.trace_id),
+                "spanId": encode_hex(&s.span_id),
+                "parentSpanId": s.parent_span_id.map(|p| encode_hex(&p)).unwrap_or_default(),
+                "name": s.name,
+                "kind": s.kind,
+                "startTimeUnixNano": s.start_unix_nano.to_string(),
+                "endTimeUnixNano": s.end_unix_nano.to_string(),
+                "attributes": attributes,
+                "status": status,
+            })
+        })
+        .collect();
+
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [{"key": "service.name", "value": {"stringValue": SERVICE_NAME}}]
+            },
+            "scopeSpans": [{
+                "scope": {"name": SERVICE_NAME},
+                "spans": spans,
+            }]
+        }]
+    })
+}
+
+fn new_trace_id() -> [u8; 16] {
+    let mut id = [0u8; 16];
+    thread_rng().fill_bytes(&mut id);
+    id
+}
+
+fn new_span_id() -> [u8; 8] {
+    let mut id = [0u8; 8];
+    thread_rng().fill_bytes(&mut id);
+    id
+}
+
+fn now_unix_nano() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    // only lowercase hex is valid in traceparent
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traced, TraceContext, Tracer};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_traceparent() {
+        let tp = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let ctx = TraceContext::parse_traceparent(tp).unwrap();
+        assert!(ctx.sampled);
+        assert_eq!(
+            ctx.span_id,
+            [0x00, 0xf0, 0x67, 0xaa, 0x0b, 0xa9, 0x02, 0xb7]
+        );
+        assert_eq!(ctx.to_traceparent(), tp);
+
+        let ctx = TraceContext::parse_traceparent(
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00",
+        )
+        .unwrap();
+        assert!(!ctx.sampled);
+
+        [
+            "",
+            "garbage",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01-extra",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e47-00f067aa0ba902b7-01",
+        ]
+        .iter()
+        .for_each(|tp| assert_eq!(TraceContext::parse_traceparent(tp), None, "{}", tp));
+    }
+
+    #[test]
+    fn test_disabled_tracer() {
+        let tracer = Tracer::disabled();
+        let _span = tracer.start_request_span("GET /ping".to_string(), "");
+        let res: Result<i32, String> = traced("redis GET", || Ok(1));
+        assert_eq!(res, Ok(1));
+    }
+
+    #[test]
+    fn test_export_to_collector() {
+        // a stand-in collector, accepting a single export request
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+
+        let tracer = Tracer::with_otlp_exporter(&endpoint).unwrap();
+        {
+            let mut span = tracer.start_request_span(
+                "GET /l/{id}".to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            );
+            span.set_attribute("http.status_code", "301".to_string());
+            let res: Result<(), String> = traced("redis GET", || Err("conn refused".to_string()));
+            assert!(res.is_err());
+        }
+        drop(tracer);
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut request = vec![];
+        let mut buf = [0u8; 4096];
+        while !String::from_utf8_lossy(&request).contains("\"scopeSpans\"")
+            || !String::from_utf8_lossy(&request).ends_with('}')
+        {
+            let n = stream.read(&mut buf).unwrap();
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+            .unwrap();
+
+        let request = String::from_utf8_lossy(&request);
+        assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Type: application/json"));
+        let body: serde_json::Value =
+            serde_json::from_str(request.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        let spans = &body["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(spans.as_array().unwrap().len(), 2);
+
+        let (redis_span, request_span) = (&spans[0], &spans[1]);
+        assert_eq!(request_span["name"], "GET /l/{id}");
+        assert_eq!(request_span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(request_span["parentSpanId"], "00f067aa0ba902b7");
+        assert_eq!(request_span["kind"], 2);
+        assert_eq!(request_span["attributes"][0]["key"], "http.status_code");
+        assert_eq!(redis_span["name"], "redis GET");
+        assert_eq!(redis_span["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(redis_span["parentSpanId"], request_span["spanId"]);
+        assert_eq!(redis_span["status"]["code"], 2);
+        assert_eq!(redis_span["status"]["message"], "conn refused");
+    }
+
+    #[test]
+    fn test_invalid_endpoint() {
+        assert!(Tracer::with_otlp_exporter("not a url").is_err());
+        assert!(Tracer::with_otlp_exporter("https://collector:4318").is_err());
+    }
+}
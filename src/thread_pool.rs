@@ -1,10 +1,35 @@
 use log::debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 
 pub struct ThreadPool {
     workers: Vec<Worker>,
     sender: mpsc::Sender<Message>,
+    stats: Arc<PoolStats>,
+}
+
+// PoolStats tells how loaded the thread pool is at the moment
+pub struct PoolStats {
+    size: usize,
+    queued: AtomicUsize,
+    busy: AtomicUsize,
+}
+
+impl PoolStats {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    // queued returns the number of jobs waiting for a free worker
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    // busy returns the number of workers executing a job
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::Relaxed)
+    }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
@@ -25,13 +50,22 @@ impl ThreadPool {
         let (sender, receiver) = mpsc::channel();
 
         let receiver = Arc::new(Mutex::new(receiver));
+        let stats = Arc::new(PoolStats {
+            size,
+            queued: AtomicUsize::new(0),
+            busy: AtomicUsize::new(0),
+        });
 
         let mut workers = Vec::with_capacity(size);
         for id in 0..size {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
+            workers.push(Worker::new(id, Arc::clone(&receiver), Arc::clone(&stats)));
         }
 
-        ThreadPool { workers, sender }
+        ThreadPool {
+            workers,
+            sender,
+            stats,
+        }
     }
 
     pub fn execute<F>(&self, f: F)
@@ -39,8 +73,13 @@ impl ThreadPool {
         F: FnOnce() + Send + 'static,
     {
         let job = Box::new(f);
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
+
+    pub fn stats(&self) -> Arc<PoolStats> {
+        Arc::clone(&self.stats)
+    }
 }
 
 impl Drop for ThreadPool {
@@ -63,17 +102,42 @@ impl Drop for ThreadPool {
     }
 }
 
+// BusyGuard counts a worker as busy for as long as it lives, so the gauge
+// is also decremented when a job panics and unwinds the worker
+struct BusyGuard<'a> {
+    stats: &'a PoolStats,
+}
+
+impl<'a> BusyGuard<'a> {
+    fn new(stats: &'a PoolStats) -> BusyGuard<'a> {
+        stats.busy.fetch_add(1, Ordering::Relaxed);
+        BusyGuard { stats }
+    }
+}
+
+impl Drop for BusyGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.busy.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 struct Worker {
     id: usize,
     thread: Option<thread::JoinHandle<()>>,
 }
 
 impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
+    fn new(
+        id: usize,
+        receiver: Arc<Mutex<mpsc::Receiver<Message>>>,
+        stats: Arc<PoolStats>,
+    ) -> Worker {
         let thread = thread::spawn(move || loop {
             let message = receiver.lock().unwrap().recv().unwrap();
             match message {
                 Message::NewJob(job) => {
+                    stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    let _busy = BusyGuard::new(&stats);
                     debug!("worker {} got a new job, executing ...", id);
                     job();
                     debug!("worker {} job done", id);
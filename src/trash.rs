@@ -0,0 +1,120 @@
+use log::debug;
+use redis::{Commands, Connection, RedisError, Script};
+use std::collections::HashSet;
+
+extern crate redis;
+use crate::{alias_handler::alias_key, analytics, history, telemetry, url_record::URLRecord};
+
+// keys of the deleted links, they're kept out of short_urls until restored
+pub const TRASH_SET: &str = "trashed_urls";
+
+// PURGE_SCRIPT deletes the link (KEYS[2]) with its aliases (KEYS[3..]) and takes it out of the
+// trash (KEYS[1]), only if it's still in the trash since before the cutoff (ARGV[1]), so a link
+// restored after the trash was read is left alone
+const PURGE_SCRIPT: &str = r#"
+local url_record = redis.call('GET', KEYS[2])
+if not url_record then
+    return 0
+end
+local deleted_at = cjson.decode(url_record)['deleted_at']
+if type(deleted_at) ~= 'number' or deleted_at >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('DEL', unpack(KEYS, 2))
+redis.call('SREM', KEYS[1], KEYS[2])
+return 1
+"#;
+
+// get_trashed returns the links in the trash, the most recently deleted first
+pub fn get_trashed(redis_conn: &mut Connection) -> Result<Vec<URLRecord>, RedisError> {
+    let url_keys: HashSet<String> =
+        telemetry::traced("redis SMEMBERS", || redis_conn.smembers(TRASH_SET))?;
+
+    let mut url_records = vec![];
+    for url_key in &url_keys {
+        let url_id = match url_key.strip_prefix("short_url::") {
+            Some(url_id) => url_id,
+            None => continue,
+        };
+        let url_record: Option<String> =
+            telemetry::traced("redis GET", || redis_conn.get(url_key))?;
+        if let Some(url_record) = url_record {
+            url_records.push(URLRecord::from_json(url_id.to_string(), &url_record));
+        }
+    }
+    url_records.sort_by_key(|r| std::cmp::Reverse(r.deleted_at));
+    Ok(url_records)
+}
+
+// purge removes the link for good, with its aliases, click analytics and history, if it's
+// still in the trash since before cutoff (unix timestamp); returns whether it was purged
+pub fn purge(
+    redis_conn: &mut Connection,
+    url_record: &URLRecord,
+    cutoff: i64,
+) -> Result<bool, RedisError> {
+    let purge_script = Script::new(PURGE_SCRIPT);
+    let mut invocation = purge_script.prepare_invoke();
+    invocation
+        .key(TRASH_SET)
+        .key(format!("short_url::{}", url_record.id));
+    for alias in &url_record.aliases {
+        invocation.key(alias_key(alias));
+    }
+    invocation.arg(cutoff);
+    let purged: bool = telemetry::traced("redis EVALSHA", || invocation.invoke(redis_conn))?;
+    if !purged {
+        return Ok(false);
+    }
+    analytics::wipe(redis_conn, &url_record.id)?;
+    history::wipe(redis_conn, &url_record.id)?;
+    Ok(true)
+}
+
+// purge_expired removes for good the links moved to the trash before cutoff (unix timestamp),
+// returns the number of purged links
+pub fn purge_expired(redis_conn: &mut Connection, cutoff: i64) -> Result<usize, RedisError> {
+    let mut purged = 0;
+    for url_record in get_trashed(redis_conn)? {
+        if !is_purgeable(&url_record, cutoff) {
+            continue;
+        }
+        if !purge(redis_conn, &url_record, cutoff)? {
+            debug!(
+                "[{}] was restored in the meantime, not purged",
+                url_record.id
+            );
+            continue;
+        }
+        debug!("purged [{}] from the trash", url_record.id);
+        purged += 1;
+    }
+    Ok(purged)
+}
+
+fn is_purgeable(url_record: &URLRecord, cutoff: i64) -> bool {
+    url_record
+        .deleted_at
+        .is_some_and(|deleted_at| deleted_at < cutoff)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::is_purgeable;
+    use crate::url_record::URLRecord;
+
+    #[test]
+    fn test_is_purgeable() {
+        let mut url_record = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"https://2beens.xyz","timestamp":1,"hits":0}"#.to_string(),
+        );
+        // restored in the meantime
+        assert!(!is_purgeable(&url_record, 1000));
+
+        url_record.deleted_at = Some(500);
+        assert!(is_purgeable(&url_record, 1000));
+        assert!(!is_purgeable(&url_record, 500));
+        assert!(!is_purgeable(&url_record, 100));
+    }
+}
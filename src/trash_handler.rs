@@ -0,0 +1,164 @@
+use http::StatusCode;
+use log::debug;
+use redis::{Commands, Connection, RedisError};
+use serde_json::Value;
+use std::net::TcpStream;
+use std::sync::Arc;
+
+extern crate redis;
+use crate::{
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    metrics::Metrics,
+    stats_handler::get_id_param,
+    telemetry,
+    trash::{self, TRASH_SET},
+    url_record::URLRecord,
+};
+
+pub struct TrashHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+}
+
+impl TrashHandler {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<TrashHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(TrashHandler {
+            redis_conn,
+            metrics,
+        })
+    }
+
+    // handle_trash lists the deleted links which can still be restored, the most recently
+    // deleted first
+    pub fn handle_trash(&mut self, stream: TcpStream) {
+        let url_records = match trash::get_trashed(&mut self.redis_conn) {
+            Ok(url_records) => url_records,
+            Err(err) => {
+                debug!("failed to get the links in the trash: {}", err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        let url_records: Vec<Value> = url_records.iter().map(URLRecord::to_public_value).collect();
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            Value::Array(url_records).to_string(),
+        );
+    }
+
+    // handle_restore takes a link out of the trash, with its aliases, hits and history, expects
+    // the path in form of: /restore?id=<url id>
+    pub fn handle_restore(&mut self, stream: TcpStream, path: &str, actor: &str) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let url_key = format!("short_url::{}", id);
+        let mut url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::NOT_FOUND.as_u16(),
+                    format!("url [{}] not found", id),
+                );
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+        if url_record.deleted_at.is_none() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::CONFLICT.as_u16(),
+                format!("url [{}] is not in the trash", id),
+            );
+            return;
+        }
+
+        url_record.deleted_at = None;
+        url_record.version += 1;
+        let url_record_json = url_record.to_json();
+        debug!(
+            "++ storing restored url record: {}",
+            url_record.to_public_value()
+        );
+        let _: () = match telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, &url_record_json)
+        }) {
+            Ok(val) => val,
+            Err(err) => {
+                debug!(
+                    "failed to execute SET for restored url key [{}]: {}",
+                    url_key, err
+                );
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        // now move the key from the trash back to the short_urls set
+        let res: Result<i32, _> = telemetry::traced("redis SADD", || {
+            self.redis_conn.sadd("short_urls", &url_key)
+        });
+        if let Err(err) = res {
+            debug!("failed to add {} to short_urls set: {}", url_key, err);
+            self.metrics.inc_redis_errors();
+        }
+        let res: Result<i32, _> =
+            telemetry::traced("redis SREM", || self.redis_conn.srem(TRASH_SET, &url_key));
+        if let Err(err) = res {
+            debug!("failed to delete {} from the trash set: {}", url_key, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        let entry = HistoryEntry::new(&url_record, actor, "restore");
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the restore of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            url_record.to_public_value().to_string(),
+        );
+    }
+}
@@ -0,0 +1,316 @@
+use chrono::Utc;
+use http::StatusCode;
+use log::debug;
+use redis::{Commands, Connection, RedisError};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+extern crate redis;
+use crate::{
+    alias_handler::respond_not_found,
+    handlers::Handlers,
+    history::{self, HistoryEntry},
+    link_password::hash_password,
+    metrics::Metrics,
+    new_handler::{
+        get_link_data_from_post_body, prepare_schedule, prepare_targets, prepare_url,
+        prepare_variants,
+    },
+    stats_handler::get_id_param,
+    telemetry,
+    url_record::URLRecord,
+};
+
+pub struct UpdateHandler {
+    redis_conn: Connection,
+    metrics: Arc<Metrics>,
+    // links to these domains, and their subdomains, can't be set
+    blocked_domains: Vec<String>,
+}
+
+impl UpdateHandler {
+    pub fn new(
+        redis_conn_string: &String,
+        metrics: Arc<Metrics>,
+    ) -> Result<UpdateHandler, RedisError> {
+        let redis_client = redis::Client::open(String::from(redis_conn_string))?;
+        let redis_conn = redis_client.get_connection()?;
+        Ok(UpdateHandler {
+            redis_conn,
+            metrics,
+            blocked_domains: vec![],
+        })
+    }
+
+    pub fn set_blocked_domains(&mut self, blocked_domains: &[String]) {
+        self.blocked_domains = blocked_domains
+            .iter()
+            .map(|d| d.trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+    }
+
+    // handle_update expects the path in form of: /update?id=<url id>, and the url, redirect_type,
+    // starts_at, expires_at, forward_path, preview, variants, targets, schedule and/or password
+    // to set in the post body; a starts_at or expires_at of 0, or an empty password, removes it
+    pub fn handle_update(
+        &mut self,
+        stream: TcpStream,
+        path: &str,
+        post_body: String,
+        content_type: String,
+        actor: &str,
+    ) {
+        let id = match get_id_param(path) {
+            Some(id) => id,
+            None => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("missing url id info"),
+                );
+                return;
+            }
+        };
+        log_mdc::insert("link_id", id);
+
+        let link_data = match get_link_data_from_post_body(post_body, content_type) {
+            Ok(link_data) => link_data,
+            Err(err) => {
+                debug!("update url: {}", err);
+                Handlers::respond_with_status_code(stream, StatusCode::BAD_REQUEST.as_u16(), err);
+                return;
+            }
+        };
+        if link_data.url.is_empty()
+            && link_data.redirect_type.is_none()
+            && link_data.expires_at.is_none()
+            && link_data.forward_path.is_none()
+            && link_data.preview.is_none()
+            && link_data.variants.is_none()
+            && link_data.targets.is_none()
+            && link_data.starts_at.is_none()
+            && link_data.schedule.is_none()
+            && link_data.password.is_none()
+        {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                String::from("nothing to update"),
+            );
+            return;
+        }
+        if link_data.utm.is_some() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::BAD_REQUEST.as_u16(),
+                String::from("utm can only be set when creating a link"),
+            );
+            return;
+        }
+        let expires_at = match link_data.expires_at {
+            Some(0) => Some(None),
+            Some(expires_at) if expires_at <= Utc::now().timestamp() => {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("expires_at is in the past"),
+                );
+                return;
+            }
+            Some(expires_at) => Some(Some(expires_at)),
+            None => None,
+        };
+        let starts_at = link_data.starts_at.map(|t| Some(t).filter(|t| *t > 0));
+        let password_hash = match link_data.password {
+            Some(password) if password.is_empty() => Some(None),
+            Some(password) => match hash_password(&password) {
+                Ok(password_hash) => Some(Some(password_hash)),
+                Err(err) => {
+                    debug!("update url: {}", err);
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                        err,
+                    );
+                    return;
+                }
+            },
+            None => None,
+        };
+        let url_key = format!("short_url::{}", id);
+        let mut url_record = match telemetry::traced("redis GET", || {
+            self.redis_conn.get::<&String, Option<String>>(&url_key)
+        }) {
+            Ok(Some(url_record)) => URLRecord::from_json(id.to_string(), &url_record),
+            Ok(None) => {
+                respond_not_found(&mut self.redis_conn, &self.metrics, stream, id);
+                return;
+            }
+            Err(err) => {
+                debug!("failed to execute GET for [{}]: {}", url_key, err);
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        if url_record.deleted_at.is_some() {
+            Handlers::respond_with_status_code(
+                stream,
+                StatusCode::CONFLICT.as_u16(),
+                format!("url [{}] is in the trash, restore it first", id),
+            );
+            return;
+        }
+        if let Err(err) = history::record_baseline(&mut self.redis_conn, &mut url_record) {
+            debug!("failed to record the history baseline of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        // the campaign params the link was created with stay on its urls
+        let utm = url_record.utm.clone();
+        let url = if link_data.url.is_empty() {
+            None
+        } else {
+            match prepare_url(&link_data.url, utm.as_ref(), &self.blocked_domains) {
+                Ok(url) => Some(url),
+                Err(err) => {
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::BAD_REQUEST.as_u16(),
+                        err,
+                    );
+                    return;
+                }
+            }
+        };
+        let variants = match link_data.variants {
+            Some(variants) => {
+                match prepare_variants(variants, utm.as_ref(), &self.blocked_domains) {
+                    Ok(variants) => Some(variants),
+                    Err(err) => {
+                        Handlers::respond_with_status_code(
+                            stream,
+                            StatusCode::BAD_REQUEST.as_u16(),
+                            err,
+                        );
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+        let targets = match link_data.targets {
+            Some(targets) => match prepare_targets(targets, utm.as_ref(), &self.blocked_domains) {
+                Ok(targets) => Some(targets),
+                Err(err) => {
+                    Handlers::respond_with_status_code(
+                        stream,
+                        StatusCode::BAD_REQUEST.as_u16(),
+                        err,
+                    );
+                    return;
+                }
+            },
+            None => None,
+        };
+        let schedule = match link_data.schedule {
+            Some(schedule) => {
+                match prepare_schedule(schedule, utm.as_ref(), &self.blocked_domains) {
+                    Ok(schedule) => Some(schedule),
+                    Err(err) => {
+                        Handlers::respond_with_status_code(
+                            stream,
+                            StatusCode::BAD_REQUEST.as_u16(),
+                            err,
+                        );
+                        return;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(url) = url {
+            url_record.url = url;
+        }
+        if link_data.redirect_type.is_some() {
+            url_record.redirect_type = link_data.redirect_type;
+        }
+        if let Some(expires_at) = expires_at {
+            url_record.expires_at = expires_at;
+        }
+        if let Some(forward_path) = link_data.forward_path {
+            url_record.forward_path = forward_path;
+        }
+        if let Some(preview) = link_data.preview {
+            url_record.preview = preview;
+        }
+        if let Some(variants) = variants {
+            url_record.variants = variants;
+        }
+        if let Some(targets) = targets {
+            url_record.targets = targets;
+        }
+        if let Some(starts_at) = starts_at {
+            url_record.starts_at = starts_at;
+        }
+        if let Some(schedule) = schedule {
+            url_record.schedule = schedule;
+        }
+        if let Some(password_hash) = password_hash {
+            url_record.password_hash = password_hash;
+        }
+        if let (Some(starts_at), Some(expires_at)) = (url_record.starts_at, url_record.expires_at) {
+            if starts_at >= expires_at {
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::BAD_REQUEST.as_u16(),
+                    String::from("starts_at must be before expires_at"),
+                );
+                return;
+            }
+        }
+
+        url_record.version += 1;
+        let url_record_json = url_record.to_json();
+        debug!(
+            "++ storing updated url record: {}",
+            url_record.to_public_value()
+        );
+        let _: () = match telemetry::traced("redis SET", || {
+            self.redis_conn.set(&url_key, &url_record_json)
+        }) {
+            Ok(val) => val,
+            Err(err) => {
+                debug!(
+                    "failed to execute SET for updated url key [{}]: {}",
+                    url_key, err
+                );
+                self.metrics.inc_redis_errors();
+                Handlers::respond_with_status_code(
+                    stream,
+                    StatusCode::INTERNAL_SERVER_ERROR.as_u16(),
+                    err.to_string(),
+                );
+                return;
+            }
+        };
+
+        let entry = HistoryEntry::new(&url_record, actor, "update");
+        if let Err(err) = history::record_change(&mut self.redis_conn, &entry) {
+            debug!("failed to record the update of [{}]: {}", id, err);
+            self.metrics.inc_redis_errors();
+        }
+
+        Handlers::json_response(
+            stream,
+            StatusCode::OK.as_u16(),
+            url_record.to_public_value().to_string(),
+        );
+    }
+}
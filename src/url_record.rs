@@ -1,33 +1,492 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
 
-#[derive(Serialize, Deserialize, Debug)]
+// status codes links can redirect with; 301 and 308 are cached by browsers, 302 and 307 aren't
+pub const REDIRECT_TYPES: [u16; 4] = [301, 302, 307, 308];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct URLRecord {
     pub id: String,
     pub url: String,
     pub timestamp: i64,
     pub hits: i32,
+    // redirect status code of this link, the configured default is used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub redirect_type: Option<u16>,
+    // unix timestamp after which the link stops redirecting, it never expires when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+    // whether what follows the id in the short link is appended to the url, e.g. /l/docs/faq
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forward_path: bool,
+    // campaign parameters merged into the url when the link was created
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub utm: Option<Utm>,
+    // weighted destinations of an a/b split link, one of them is picked for each click
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+    // destinations for given platforms and languages, the first matching one is used and the
+    // url is the fallback
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<Target>,
+    // unix timestamp before which the link doesn't redirect yet, it's active right away when
+    // not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub starts_at: Option<i64>,
+    // destinations taking over the url from their time on, e.g. the recording after an event
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduledUrl>,
+    // argon2 hash of the password visitors have to enter before being redirected, never shown
+    // to api clients
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_hash: Option<String>,
+    // whether the link shows a preview page with where it goes, instead of redirecting
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preview: bool,
+    // extra ids the link can be reached through, sharing its destination and hits
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    // bumped with each change to the link, records from before the history have 0
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub version: u32,
+    // unix timestamp of when the link was moved to the trash, it's purged after the retention
+    // period unless restored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// how many aliases a link can have
+pub const MAX_ALIASES: usize = 10;
+
+// how many destinations a split link can have
+pub const MAX_VARIANTS: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    pub url: String,
+    // share of the clicks, relative to the other variants
+    pub weight: u32,
+}
+
+// how many scheduled destinations a link can have
+pub const MAX_SCHEDULED_URLS: usize = 10;
+
+// ScheduledUrl is the destination of a link from the given time on, until the next one
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledUrl {
+    // unix timestamp
+    pub from: i64,
+    pub url: String,
+}
+
+// client platforms a link can target, as told by the User-Agent
+pub const PLATFORMS: [&str; 3] = ["ios", "android", "desktop"];
+
+// how many targeted destinations a link can have
+pub const MAX_TARGETS: usize = 10;
+
+// Target sends the clients on the platform and/or with the language to its url
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    // language tag, e.g. "de" (matching "de-AT" as well) or "pt-BR"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    pub url: String,
+}
+
+impl Target {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.platform.is_none() && self.language.is_none() {
+            return Err("a target needs a platform or a language".to_string());
+        }
+        if let Some(platform) = &self.platform {
+            if !PLATFORMS.contains(&platform.as_str()) {
+                return Err(format!(
+                    "invalid platform {}, expected one of {:?}",
+                    platform, PLATFORMS
+                ));
+            }
+        }
+        if let Some(language) = &self.language {
+            let valid = !language.is_empty()
+                && language.len() <= 35
+                && language.split('-').all(|part| {
+                    !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric())
+                });
+            if !valid {
+                return Err(format!("invalid language {}", language));
+            }
+        }
+        Ok(())
+    }
+
+    // matches tells if the target is for the client platform and its preferred language
+    pub fn matches(&self, platform: Option<&str>, language: Option<&str>) -> bool {
+        let platform_matches = match &self.platform {
+            Some(target_platform) => platform == Some(target_platform.as_str()),
+            None => true,
+        };
+        let language_matches = match (&self.language, language) {
+            (Some(target_language), Some(language)) => is_language_match(target_language, language),
+            (Some(_), None) => false,
+            (None, _) => true,
+        };
+        platform_matches && language_matches
+    }
+}
+
+// is_language_match tells if the language is the target one, or one of its regional variants
+fn is_language_match(target_language: &str, language: &str) -> bool {
+    match language.get(..target_language.len()) {
+        Some(prefix) => {
+            prefix.eq_ignore_ascii_case(target_language)
+                && matches!(
+                    language.as_bytes().get(target_language.len()),
+                    None | Some(b'-')
+                )
+        }
+        None => false,
+    }
+}
+
+// max length of a single utm value
+const MAX_UTM_VALUE_LEN: usize = 200;
+
+// Utm holds the campaign parameters of a link, added to its url as utm_<name>=<value>
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Utm {
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+}
+
+impl Utm {
+    // params returns the set query params, in the usual order
+    pub fn params(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("utm_source", Some(&self.source)),
+            ("utm_medium", self.medium.as_ref()),
+            ("utm_campaign", self.campaign.as_ref()),
+            ("utm_term", self.term.as_ref()),
+            ("utm_content", self.content.as_ref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, value)| value.map(|v| (name, v.as_str())))
+        .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in self.params() {
+            if value.trim().is_empty() {
+                return Err(format!("{} can't be empty", name));
+            }
+            if value.len() > MAX_UTM_VALUE_LEN {
+                return Err(format!(
+                    "{} is longer than {} characters",
+                    name, MAX_UTM_VALUE_LEN
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // apply_to adds the params to the query of the url, replacing the utm params already there
+    pub fn apply_to(&self, url: &str) -> Result<String, String> {
+        let mut url = Url::parse(url).map_err(|e| e.to_string())?;
+        let params = self.params();
+        let mut query: Vec<(String, String)> = url
+            .query_pairs()
+            .into_owned()
+            .filter(|(name, _)| !params.iter().any(|(n, _)| n == name))
+            .collect();
+        query.extend(params.iter().map(|(n, v)| (n.to_string(), v.to_string())));
+        url.query_pairs_mut().clear().extend_pairs(query);
+        Ok(url.to_string())
+    }
 }
 
 impl URLRecord {
     // from_json will try go unmarshal, but for backwards compatility, this funciton also
     // needs the original id for backfill... not nice
     pub fn from_json(id: String, json: &String) -> URLRecord {
-        return match serde_json::from_str(&json) {
+        match serde_json::from_str(json) {
             Ok(val) => val,
             Err(_) => {
                 // backwards compatibility: ignore err, url is (most likely) from the previous model
                 // which contained only the url itself
-                return URLRecord {
-                    id: id,
+                URLRecord {
+                    id,
                     timestamp: 0,
                     url: json.to_string(),
                     hits: 0,
-                };
+                    redirect_type: None,
+                    expires_at: None,
+                    forward_path: false,
+                    utm: None,
+                    variants: vec![],
+                    targets: vec![],
+                    starts_at: None,
+                    schedule: vec![],
+                    password_hash: None,
+                    preview: false,
+                    aliases: vec![],
+                    version: 0,
+                    deleted_at: None,
+                }
             }
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn has_started(&self, now: i64) -> bool {
+        self.starts_at.is_none_or(|starts_at| starts_at <= now)
+    }
+
+    // current_url returns the destination of the link at the given time, the scheduled one
+    // which started last, or the url when none has started yet
+    pub fn current_url(&self, now: i64) -> &str {
+        self.schedule
+            .iter()
+            .filter(|s| s.from <= now)
+            .max_by_key(|s| s.from)
+            .map_or(&self.url, |s| &s.url)
+    }
+
+    // next_change returns when the link next expires or switches its destination, redirects
+    // shouldn't be cached beyond that
+    pub fn next_change(&self, now: i64) -> Option<i64> {
+        self.schedule
+            .iter()
+            .map(|s| s.from)
+            .filter(|from| *from > now)
+            .chain(self.expires_at)
+            .min()
+    }
+
+    // urls returns every destination the link can go to
+    pub fn urls(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.url.as_str())
+            .chain(self.variants.iter().map(|v| v.url.as_str()))
+            .chain(self.targets.iter().map(|t| t.url.as_str()))
+            .chain(self.schedule.iter().map(|s| s.url.as_str()))
+    }
+
+    // restore takes over the settings of an earlier version of the link; its id, creation
+    // time, hits, aliases, version and whether it's in the trash stay as they are
+    pub fn restore(&mut self, snapshot: URLRecord) {
+        *self = URLRecord {
+            id: std::mem::take(&mut self.id),
+            timestamp: self.timestamp,
+            hits: self.hits,
+            aliases: std::mem::take(&mut self.aliases),
+            version: self.version,
+            deleted_at: self.deleted_at,
+            ..snapshot
         };
     }
 
     pub fn to_json(&self) -> String {
-        return serde_json::to_string(self).unwrap();
+        serde_json::to_string(self).unwrap()
+    }
+
+    // to_public_value is the record as shown to api clients, telling only whether the link is
+    // password protected instead of the hash
+    pub fn to_public_value(&self) -> Value {
+        let mut value = serde_json::to_value(self).unwrap();
+        if let Some(fields) = value.as_object_mut() {
+            if fields.remove("password_hash").is_some() {
+                fields.insert("password_protected".to_string(), Value::Bool(true));
+            }
+        }
+        value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ScheduledUrl, Target, URLRecord, Utm};
+
+    #[test]
+    fn test_utm_apply_to() {
+        let utm = Utm {
+            source: "newsletter".to_string(),
+            medium: Some("email".to_string()),
+            campaign: Some("spring sale".to_string()),
+            ..Utm::default()
+        };
+        assert_eq!(
+            utm.apply_to("https://shop.example.com/offers?ref=x&utm_source=old#top"),
+            Ok("https://shop.example.com/offers?ref=x&utm_source=newsletter&utm_medium=email&utm_campaign=spring+sale#top".to_string())
+        );
+        assert_eq!(
+            utm.apply_to("https://shop.example.com"),
+            Ok("https://shop.example.com/?utm_source=newsletter&utm_medium=email&utm_campaign=spring+sale".to_string())
+        );
+    }
+
+    #[test]
+    fn test_utm_validate() {
+        let mut utm = Utm {
+            source: "newsletter".to_string(),
+            ..Utm::default()
+        };
+        assert_eq!(utm.validate(), Ok(()));
+        utm.campaign = Some(" ".to_string());
+        assert_eq!(
+            utm.validate(),
+            Err("utm_campaign can't be empty".to_string())
+        );
+        utm.campaign = Some("x".repeat(201));
+        assert!(utm.validate().is_err());
+    }
+
+    #[test]
+    fn test_from_json_without_utm() {
+        let url_record = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"http://2beens.xyz","timestamp":1,"hits":2}"#.to_string(),
+        );
+        assert_eq!(url_record.utm, None);
+        assert_eq!(
+            url_record.to_json(),
+            r#"{"id":"abc","url":"http://2beens.xyz","timestamp":1,"hits":2}"#
+        );
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut url_record = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"https://2beens.xyz/new","timestamp":1,"hits":7,"aliases":["docs"],"version":3,"preview":true}"#.to_string(),
+        );
+        let snapshot = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"https://2beens.xyz/old","timestamp":1,"hits":2,"expires_at":100,"version":1,"schedule":[{"from":50,"url":"https://2beens.xyz/later"}]}"#.to_string(),
+        );
+        url_record.restore(snapshot);
+        assert_eq!(url_record.url, "https://2beens.xyz/old");
+        assert_eq!(url_record.expires_at, Some(100));
+        assert!(!url_record.preview);
+        assert_eq!(url_record.hits, 7);
+        assert_eq!(url_record.aliases, vec!["docs".to_string()]);
+        assert_eq!(url_record.version, 3);
+        assert_eq!(
+            url_record.urls().collect::<Vec<_>>(),
+            vec!["https://2beens.xyz/old", "https://2beens.xyz/later"]
+        );
+    }
+
+    #[test]
+    fn test_target_matches() {
+        let target = Target {
+            platform: Some("ios".to_string()),
+            language: None,
+            url: "https://apps.apple.com/app/id1".to_string(),
+        };
+        assert!(target.matches(Some("ios"), None));
+        assert!(target.matches(Some("ios"), Some("de")));
+        assert!(!target.matches(Some("android"), None));
+        assert!(!target.matches(None, None));
+
+        let target = Target {
+            platform: Some("desktop".to_string()),
+            language: Some("de".to_string()),
+            url: "https://2beens.xyz/de".to_string(),
+        };
+        assert!(target.matches(Some("desktop"), Some("de")));
+        assert!(target.matches(Some("desktop"), Some("DE-at")));
+        assert!(!target.matches(Some("desktop"), Some("den")));
+        assert!(!target.matches(Some("desktop"), Some("en")));
+        assert!(!target.matches(Some("desktop"), None));
+        assert!(!target.matches(Some("ios"), Some("de")));
+    }
+
+    #[test]
+    fn test_target_validate() {
+        let mut target = Target {
+            platform: None,
+            language: None,
+            url: "https://2beens.xyz".to_string(),
+        };
+        assert!(target.validate().is_err());
+        target.language = Some("pt-BR".to_string());
+        assert_eq!(target.validate(), Ok(()));
+        target.language = Some("pt_BR".to_string());
+        assert!(target.validate().is_err());
+        target.language = None;
+        target.platform = Some("android".to_string());
+        assert_eq!(target.validate(), Ok(()));
+        target.platform = Some("windows".to_string());
+        assert!(target.validate().is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        let mut url_record = URLRecord::from_json(
+            "event".to_string(),
+            &r#"{"id":"event","url":"https://2beens.xyz/event","timestamp":1,"hits":0}"#
+                .to_string(),
+        );
+        url_record.starts_at = Some(100);
+        url_record.expires_at = Some(1000);
+        url_record.schedule = vec![
+            ScheduledUrl {
+                from: 500,
+                url: "https://2beens.xyz/recording".to_string(),
+            },
+            ScheduledUrl {
+                from: 200,
+                url: "https://2beens.xyz/live".to_string(),
+            },
+        ];
+
+        assert!(!url_record.has_started(99));
+        assert!(url_record.has_started(100));
+        assert_eq!(url_record.current_url(100), "https://2beens.xyz/event");
+        assert_eq!(url_record.current_url(200), "https://2beens.xyz/live");
+        assert_eq!(url_record.current_url(999), "https://2beens.xyz/recording");
+        assert_eq!(url_record.next_change(100), Some(200));
+        assert_eq!(url_record.next_change(200), Some(500));
+        assert_eq!(url_record.next_change(500), Some(1000));
+        url_record.expires_at = None;
+        assert_eq!(url_record.next_change(500), None);
+    }
+
+    #[test]
+    fn test_to_public_value() {
+        let mut url_record = URLRecord::from_json(
+            "abc".to_string(),
+            &r#"{"id":"abc","url":"http://2beens.xyz","timestamp":1,"hits":2}"#.to_string(),
+        );
+        assert_eq!(
+            url_record.to_public_value().to_string(),
+            r#"{"hits":2,"id":"abc","timestamp":1,"url":"http://2beens.xyz"}"#
+        );
+        url_record.password_hash = Some("$argon2id$v=19$m=19456,t=2,p=1$salt$hash".to_string());
+        assert!(url_record.to_json().contains("password_hash"));
+        assert_eq!(
+            url_record.to_public_value().to_string(),
+            r#"{"hits":2,"id":"abc","password_protected":true,"timestamp":1,"url":"http://2beens.xyz"}"#
+        );
     }
 }